@@ -25,9 +25,15 @@ export default class App extends React.PureComponent {
 					<Route exact path="/" render={({ history }) =>
 						<HomePage navigate={path => history.push(path)}/>
 					}/>
-					<Route exact path="/thread/:id" render={({ match }) =>
-						<ThreadPage tail={match.params.id} />
-					}/>
+					<Route exact path="/thread/:tail/:head?" render={({ match, location }) => {
+						const query = new URLSearchParams(location.search)
+						return <ThreadPage
+							tail={match.params.tail}
+							head={match.params.head}
+							context={query.get("context") || undefined}
+							authorOnly={query.get("author_only") === "1"}
+						/>
+					}}/>
 					<Route exact path="/faq" render={props =>
 						<FAQPage />
 					}/>
@@ -4,10 +4,25 @@ import PropTypes from 'prop-types'
 import TweetList from 'components/TweetList.jsx'
 import Title from 'components/Title.jsx'
 
+const verifiedTitles = {
+	blue: "Verified account",
+	business: "Verified organization",
+	government: "Verified government account",
+}
+
+const VerifiedBadge = ({kind}) => kind && verifiedTitles[kind] ?
+	<span
+		className={`verified-badge verified-${kind}`}
+		title={verifiedTitles[kind]}
+	>✓</span> :
+	null
+
 export default class ThreadPage extends React.PureComponent {
 	static propTypes = {
 		head: PropTypes.string,
 		tail: PropTypes.string.isRequired,
+		context: PropTypes.string,
+		authorOnly: PropTypes.bool,
 	}
 
 	constructor(props) {
@@ -16,60 +31,158 @@ export default class ThreadPage extends React.PureComponent {
 		this.state = {
 			threadTweetIds: null,
 			author: null,
+			leader: null,
+			truncated: false,
+			replyingTo: null,
+			tweetCount: null,
+			readingMinutes: null,
+			initiallyShown: null,
+			showAll: false,
+			error: null,
 			fullyRendered: false,
 		}
 	}
 
 	componentDidMount() {
-		const {head, tail} = this.props
+		this.loadThread()
+	}
 
-		const query = head ?
-			`head=${head}&tail=${tail}` :
-			`tail=${tail}`
+	componentWillUnmount() {
+		clearTimeout(this.retryTimeout)
+	}
+
+	loadThread = () => {
+		const {head, tail, context, authorOnly} = this.props
+
+		const query = new URLSearchParams({tail})
+		if(head) query.set("head", head)
+		if(context) query.set("context", context)
+		if(authorOnly) query.set("author_only", "1")
 
 		fetch(`/api/thread?${query}`)
-		.then(response => response.json())
-		.then(content => this.setState({
-				threadTweetIds: content.thread,
-				author: content.author,
-		}))
+		.then(response => response.status === 202 ?
+			// The thread is still being built; check back shortly
+			this.retryTimeout = setTimeout(
+				this.loadThread,
+				(parseInt(response.headers.get("Retry-After")) || 2) * 1000,
+			) :
+			response.json().then(content => response.ok ?
+				this.setState({
+					threadTweetIds: content.thread,
+					author: content.author,
+					leader: content.authorship.leader,
+					truncated: content.truncated,
+					replyingTo: content.replying_to,
+					tweetCount: content.tweet_count,
+					readingMinutes: content.reading_minutes,
+					initiallyShown: content.initially_shown,
+				}) :
+				this.setState({error: content.error})
+			)
+		)
 	}
 
 	fullyRenderedCb = rendered => this.setState({
 		fullyRendered: rendered
 	})
 
+	showAll = () => this.setState({showAll: true})
+
 	render() {
-		const {threadTweetIds, author, fullyRendered} = this.state
+		const {
+			threadTweetIds, author, leader, truncated, replyingTo, tweetCount,
+			readingMinutes, initiallyShown, showAll, error, fullyRendered,
+		} = this.state
+
+		if(error) {
+			return <div className="container">
+				<Title>Thread unavailable</Title>
+				<div className="row">
+					<div className="col">
+						<div className="text-center thread-error tweet-like">
+							{error}
+						</div>
+					</div>
+				</div>
+			</div>
+		}
+
+		// Long threads are collapsed until the user asks for the rest
+		const shownTweetIds = threadTweetIds && !showAll && initiallyShown !== null ?
+			threadTweetIds.slice(0, initiallyShown) :
+			threadTweetIds
+		const hiddenCount = threadTweetIds ?
+			threadTweetIds.length - shownTweetIds.length :
+			0
+
+		// A "thread" of a single tweet is presented as just a tweet
+		const kind = threadTweetIds && threadTweetIds.length === 1 ?
+			"Tweet" :
+			"Thread"
 
 		const header = author ?
-			<h3 className="author-header">Thread by <a
+			<h3 className="author-header">{kind} by <a
 				href={`https://twitter.com/${author.handle}`}
 				target="_blank">
 				<span className="author">
-					<span className="author-name">{author.name}</span>{' '}
+					<span className="author-name">{author.name}</span>
+					<VerifiedBadge kind={author.verified}/>{' '}
 					<span className="author-handle">@{author.handle}</span>
 				</span>
 			</a></h3>:
-			<h3>Conversation</h3>
+			<h3>Conversation{leader ?
+				<span className="conversation-leader"> (mostly <a
+					href={`https://twitter.com/${leader.handle}`}
+					target="_blank">@{leader.handle}</a>)</span> :
+				null
+			}</h3>
 
 		return <div className="container">
 			<Title>{
-				author ? `Thread by @${author.handle}` :
+				author ? `${kind} by @${author.handle}` :
 				threadTweetIds ? "Conversation" :
 				"Thread"
 			}</Title>
 			<div className="row">
 				<div className="col text-center">
 					{header}
+					{tweetCount ?
+						<div className="thread-summary">
+							{tweetCount} {tweetCount === 1 ? "tweet" : "tweets"}
+							{' · '}~{readingMinutes} min read
+						</div> :
+						null
+					}
 				</div>
 			</div>
+			{truncated ?
+				<div className="row">
+					<div className="col">
+						<div className="text-center thread-truncated tweet-like">
+							This thread continues above, but the earlier tweets are unavailable
+						</div>
+					</div>
+				</div> :
+				null
+			}
+			{replyingTo ?
+				<div className="row">
+					<div className="col">
+						<div className="text-center replying-to tweet-like">
+							Replying to <a
+								href={`https://twitter.com/${replyingTo}`}
+								target="_blank">@{replyingTo}</a>
+						</div>
+					</div>
+				</div> :
+				null
+			}
 			<div className="row justify-content-center">
 				<div className="col">
 					{threadTweetIds === null ?
 						null :
 						<TweetList
-							tweetIds={threadTweetIds}
+							tweetIds={shownTweetIds}
 							fullyRendered={this.fullyRenderedCb}
 						/>
 					}
@@ -78,7 +191,15 @@ export default class ThreadPage extends React.PureComponent {
 			<div className="row">
 				<div className="col">
 					<div className="text-center thread-end tweet-like">
-						{fullyRendered ?
+						{hiddenCount > 0 ?
+							<button
+								type="button"
+								className="btn btn-outline-primary"
+								onClick={this.showAll}
+							>
+								Show {hiddenCount} more {hiddenCount === 1 ? "tweet" : "tweets"}
+							</button> :
+						fullyRendered ?
 							<span className="strike">
 								<span>End of Thread</span>
 							</span> :
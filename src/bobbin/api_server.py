@@ -1,8 +1,28 @@
-from collections import Counter
+import asyncio
+import math
 
+import aiohttp
 from aiohttp import web
 
-from bobbin import web_util
+from bobbin import web_util, thread_text, tweetbox, twitter
+from bobbin.async_cache import KeyNotFound
+
+# How long (in seconds) clients are asked to wait when all build slots are taken
+BUSY_RETRY_AFTER = 10
+
+# How long (in seconds) clients are asked to wait before checking on a thread
+# that's still being built
+BUILDING_RETRY_AFTER = 2
+
+# How much space to use remembering the ETags of recent thread responses, so
+# that clients can revalidate them without the thread being rebuilt
+ETAG_CACHE_SIZE = 4 * 1024 * 1024
+
+# Renderers for the non-JSON thread formats, with their content types
+TEXT_FORMATS = {
+	"text": (thread_text.render_text, "text/plain"),
+	"md": (thread_text.render_markdown, "text/markdown"),
+}
 
 
 def is_valid_tweet_id(tweet_id):
@@ -13,50 +33,214 @@ def is_valid_tweet_id(tweet_id):
 	return (1 <= len(tweet_id) <= 20) and tweet_id.isdecimal()
 
 
-def get_thread_author(thread):
-	user_counts = Counter(tweet.user for tweet in thread)
-
-	if len(user_counts) == 0:
-		return None
-	elif len(user_counts) == 1:
-		return user_counts.popitem()[0]
-
-	top_users = user_counts.most_common(2)
-
-	if top_users[0][1] > top_users[1][1] and top_users[0][1] * 2 >= len(user_counts):
-		return top_users[0][0]
-	else:
-		return None
+def user_json(user):
+	return {
+		"handle": user.handle,
+		"name": user.name,
+		"verified": user.verified,
+	} if user is not None else None
 
 
 @web_util.method_handler('GET')
 @web_util.with_query(web_util.query_error_handler_json)
 async def thread_handler(
 	request, *,
-	get_thread,
+	builds,
+	build_budget,
+	thread_etags,
+	prewarm,
+	debug_headers,
+	collapse_after,
 	tail: web_util.QueryParam,
-	head: web_util.QueryParam =None
+	head: web_util.QueryParam =None,
+	context: web_util.QueryParam =None,
+	format: web_util.QueryParam ="json",
+	author_only: web_util.QueryParam =None,
+	debug: web_util.QueryParam =None
 ):
 	if not is_valid_tweet_id(tail):
-		raise web_util.bad_request("Invalid tweet id", param="tail", tweet_id=tail)
-
-	if head is not None and not is_valid_tweet_id(head):
-		raise web_util.bad_request("Invalid tweet id", param="head", tweet_id=head)
-
-	thread = await get_thread(tail=tail, head=head)
-	thread_tweet_ids = [tweet.id for tweet in thread]
-	author = get_thread_author(thread)
-
-	return web.Response(
-		text=web_util.dump_json(
-			thread=thread_tweet_ids,
-			author={
-				"handle": author.handle,
-				"name": author.name,
-			} if author is not None else None),
-		content_type="application/json",
+		raise web_util.bad_request_json("Invalid tweet id", param="tail", tweet_id=tail)
+
+	if head is not None:
+		if not is_valid_tweet_id(head):
+			raise web_util.bad_request_json("Invalid tweet id", param="head", tweet_id=head)
+
+		# Tweet IDs are ordered by time, so a head with a larger ID than the
+		# tail is a later tweet, and the backwards walk from the tail would
+		# never reach it.
+		if int(head) > int(tail):
+			raise web_util.bad_request_json(
+				"The start tweet comes after the end tweet",
+				param="head",
+				tweet_id=head,
+			)
+
+	if context is not None:
+		if not (1 <= len(context) <= 4 and context.isdecimal()):
+			raise web_util.bad_request_json("Invalid context length", param="context", context=context)
+		context = int(context)
+
+	if author_only not in (None, "0", "1"):
+		raise web_util.bad_request_json("Invalid author_only flag", param="author_only", author_only=author_only)
+	author_only = author_only == "1"
+
+	if format != "json" and format not in TEXT_FORMATS:
+		raise web_util.bad_request_json("Invalid format", param="format", format=format)
+
+	# If we've already sent this exact response recently, and the client has
+	# it, it can be revalidated without building the thread. Tweets never
+	# change, so the response only changes if tweets are deleted, and
+	# thread_etags expires entries no later than the tweet cache does. Debug
+	# responses say where each tweet was found, which changes every build.
+	etag_key = None if debug == "1" else (tail, head, context, author_only, format)
+	thread_options = dict(tail=tail, head=head, context=context, author_only=author_only)
+
+	if etag_key is not None:
+		try:
+			etag = await thread_etags.get(etag_key)
+		except KeyNotFound:
+			pass
+		else:
+			if web_util.etag_matches(request, etag):
+				# The thread is still being read, so keep it fresh
+				if prewarm is not None:
+					prewarm.add(**thread_options)
+				raise web.HTTPNotModified(headers={"ETag": etag})
+
+	# Rather than queueing unboundedly during a traffic spike, refuse new
+	# builds once every slot is taken.
+	try:
+		build = builds.start(**thread_options)
+	except tweetbox.BuildLimitError as e:
+		raise web_util.service_unavailable_json(
+			"Too many threads are being loaded right now; try again shortly",
+			retry_after=BUSY_RETRY_AFTER,
+		) from e
+
+	# If we give up waiting, the build carries on in the background, and a
+	# later request for the same thread joins it (or, once it's done, finds
+	# its tweets in the cache).
+	await asyncio.wait([build], timeout=build_budget)
+	if not build.done():
+		return web.Response(
+			status=202,
+			text=web_util.dump_json(status="building"),
+			content_type="application/json",
+			headers={"Retry-After": str(BUILDING_RETRY_AFTER)},
+		)
+
+	try:
+		thread = build.result()
+	except twitter.TokenError as e:
+		# The token is acquired lazily, so this is where a twitter outage at
+		# startup (or a bad key) shows up.
+		raise web_util.service_unavailable_json(
+			"Bobbin couldn't authenticate with Twitter; try again shortly",
+			retry_after=BUSY_RETRY_AFTER,
+		) from e
+	except twitter.RateLimitError as e:
+		raise web_util.service_unavailable_json(
+			"Bobbin is being rate limited by Twitter; try again shortly",
+			retry_after=(
+				BUSY_RETRY_AFTER if e.retry_after is None
+				else max(math.ceil(e.retry_after), 1)
+			),
+		) from e
+	except (aiohttp.ClientError, asyncio.TimeoutError, twitter.AuthenticationError) as e:
+		# Network trouble, twitter server errors, and twitter rejecting our
+		# token are all (hopefully) temporary. Any other error status means
+		# the request itself is wrong, and retrying it won't help.
+		if isinstance(e, aiohttp.ClientResponseError) and e.status < 500:
+			raise
+
+		raise web_util.service_unavailable_json(
+			"Bobbin couldn't reach Twitter; try again shortly",
+			retry_after=BUSY_RETRY_AFTER,
+		) from e
+	except twitter.DeletedTweetError as e:
+		# The tail definitely existed, and is gone, rather than temporarily
+		# unavailable.
+		raise web_util.gone_json("This tweet has been deleted", tweet_id=tail) from e
+	except twitter.TwitterIDError as e:
+		# Usually, the tweet never existed, or the author is suspended or no
+		# longer exists
+		raise web_util.not_found_json("This tweet is unavailable", tweet_id=tail) from e
+	except tweetbox.MissingAncestorError as e:
+		raise web_util.not_found_json(
+			"An earlier tweet in this thread is unavailable",
+			tweet_id=e.args[0],
+		) from e
+	except tweetbox.InvalidThreadError as e:
+		raise web_util.not_found_json(
+			"The start tweet isn't part of this thread",
+			param="head",
+			tweet_id=head,
+		) from e
+
+	if prewarm is not None:
+		prewarm.add(**thread_options)
+
+	authorship = thread.authorship()
+	author = authorship.author
+
+	headers = {}
+	if debug_headers:
+		stats = thread.stats
+		headers["X-Bobbin-Cache"] = (
+			f"hits={stats['cache_hits']};"
+			f"misses={stats['cache_misses']};"
+			f"api={stats['api_calls']}"
+		)
+
+	async def thread_response(text, content_type):
+		if etag_key is not None:
+			await thread_etags.write(etag_key, web_util.make_etag(text))
+
+		return web_util.conditional_response(
+			request,
+			text=text,
+			content_type=content_type,
+			headers=headers,
+		)
+
+	if format in TEXT_FORMATS:
+		render, content_type = TEXT_FORMATS[format]
+		return await thread_response(render(thread, author), content_type)
+
+	thread_tweet_ids = [tweet.id for tweet in thread.tweets]
+
+	# If the thread doesn't start at the beginning of the conversation, say
+	# who the first tweet replies to. Twitter includes the handle in the
+	# tweet, so this doesn't need an extra user lookup.
+	first_tweet = thread.tweets[0] if thread.tweets else None
+	replying_to = first_tweet.parent_user_handle if first_tweet is not None else None
+
+	content = dict(
+		thread=thread_tweet_ids,
+		share_path=thread.share_path(),
+		tweet_count=thread.tweet_count(),
+		reading_minutes=thread.reading_minutes(),
+		# How many tweets to show before a "show more" control
+		initially_shown=(
+			len(thread_tweet_ids) if collapse_after is None
+			else min(len(thread_tweet_ids), collapse_after)
+		),
+		truncated=thread.truncated,
+		replying_to=replying_to,
+		author=user_json(author),
+		authorship={
+			"leader": user_json(authorship.leader),
+			"share": authorship.share,
+			"participants": authorship.participants,
+		},
 	)
 
+	# For checking that the cache is working: where each tweet was found
+	if debug == "1":
+		content["sources"] = [thread.sources[tweet_id] for tweet_id in thread_tweet_ids]
+
+	return await thread_response(web_util.dump_json(**content), "application/json")
+
 
 handler = web_util.routes(
 	(r"/thread/?$", thread_handler)
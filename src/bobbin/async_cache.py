@@ -3,6 +3,9 @@ import contextlib
 import collections
 import itertools
 import sys
+import time
+
+import cachetools
 
 from bobbin import task_manager
 
@@ -117,6 +120,30 @@ class SyncStackedCache(BaseUpdaterStackedCache):
 		return result
 
 
+class AsyncLRUCache(Cache):
+	'''
+	An in-memory cache that evicts the least recently used entries once the
+	total size (as measured by get_size_of) of its entries exceeds max_size.
+	If ttl is given, entries also expire that many seconds after they were
+	written, as measured by timer.
+	'''
+	def __init__(self, max_size, *, ttl=None, timer=time.time):
+		if ttl is None:
+			self.cache = cachetools.LRUCache(max_size, getsizeof=get_size_of)
+		else:
+			self.cache = cachetools.TTLCache(max_size, ttl, timer=timer, getsizeof=get_size_of)
+
+	async def get(self, key):
+		with self.convert_keyerror():
+			return self.cache[key]
+
+	async def write(self, key, value):
+		self.cache[key] = value
+
+	def __len__(self):
+		return len(self.cache)
+
+
 size_handlers = {
 	list: iter,
 	tuple: iter,
@@ -1,4 +1,5 @@
 import asyncio
+import contextlib
 import functools
 
 
@@ -43,3 +44,38 @@ def shared_concurrent(func):
 		return (await asyncio.shield(task))
 
 	return shared_concurrent_wrapper
+
+
+class KeyedSemaphore:
+	'''
+	A collection of semaphores, one per key, each allowing `value` concurrent
+	holders. Semaphores are created on demand and discarded once nobody holds
+	or is waiting for them, so this can be keyed by an unbounded set of keys.
+
+	Usage:
+
+		async with keyed_semaphore.acquire(key):
+			...
+	'''
+	def __init__(self, value=1):
+		self.value = value
+		self.semaphores = {}
+
+	@contextlib.asynccontextmanager
+	async def acquire(self, key):
+		try:
+			semaphore, users = self.semaphores[key]
+		except KeyError:
+			semaphore, users = asyncio.Semaphore(self.value), 0
+
+		self.semaphores[key] = (semaphore, users + 1)
+
+		try:
+			async with semaphore:
+				yield
+		finally:
+			semaphore, users = self.semaphores[key]
+			if users == 1:
+				del self.semaphores[key]
+			else:
+				self.semaphores[key] = (semaphore, users - 1)
@@ -0,0 +1,69 @@
+# Error reporting hooks. An error sink is any callable accepting (error,
+# context), where error is an exception and context is a dict describing
+# where it happened. Sinks are called for errors that would otherwise only be
+# logged or swallowed, so they must not raise. Context should never contain
+# secrets, like credentials or tokens.
+
+import importlib
+import sys
+import traceback
+
+
+def ignore_error(error, context):
+	pass
+
+
+def print_error(error, context):
+	print(f"Error: {context}", file=sys.stderr)
+	traceback.print_exception(type(error), error, error.__traceback__)
+
+
+def load_sink(spec):
+	'''
+	Load an error sink from a "module:function" spec, such as
+	"bobbin.error_reporting:print_error"
+	'''
+	module_name, sep, attr = spec.partition(':')
+	if not sep:
+		raise ValueError(f"Error sink should be module:function, got {spec!r}")
+
+	return getattr(importlib.import_module(module_name), attr)
+
+
+def safe_report(sink, error, context):
+	'''
+	Report an error to a sink, making sure a broken sink can't cause
+	additional failures.
+	'''
+	try:
+		sink(error, context)
+	except Exception:
+		traceback.print_exc()
+
+
+def report_task_errors(task, sink, context):
+	'''
+	Report the error from a background task that nobody awaits, if it fails.
+	This also retrieves the error, so the event loop doesn't log it again.
+	Returns the task.
+	'''
+	def report_failure(task):
+		if not task.cancelled() and task.exception() is not None:
+			safe_report(sink, task.exception(), context)
+
+	task.add_done_callback(report_failure)
+	return task
+
+
+def install_loop_sink(loop, sink):
+	'''
+	Report exceptions that the event loop would otherwise only log, such as
+	those from background tasks that nobody awaited.
+	'''
+	def loop_exception_handler(loop, context):
+		error = context.get('exception')
+		if error is not None:
+			safe_report(sink, error, {"message": context.get('message')})
+		loop.default_exception_handler(context)
+
+	loop.set_exception_handler(loop_exception_handler)
@@ -1,9 +1,61 @@
 # This file serves javascript, html, etc.
 
+import base64
+import hashlib
 import pathlib
+import re
 from aiohttp import web
 from bobbin import web_util
 
+INLINE_SCRIPT = re.compile(r"<script>(.*?)</script>", re.DOTALL)
+
+# External sources used by index.html and the twitter embeds
+CSP_SOURCES = {
+	"default-src": ["'self'"],
+	"script-src": [
+		"'self'",
+		"https://www.googletagmanager.com",
+		# gtag.js loads analytics.js from here for UA- properties
+		"https://www.google-analytics.com",
+		"https://code.jquery.com",
+		"https://cdnjs.cloudflare.com",
+		"https://maxcdn.bootstrapcdn.com",
+		"https://platform.twitter.com",
+		"https://cdn.syndication.twimg.com",
+	],
+	# The webpack style-loader injects <style> tags
+	"style-src": [
+		"'self'",
+		"'unsafe-inline'",
+		"https://maxcdn.bootstrapcdn.com",
+		"https://platform.twitter.com",
+	],
+	"img-src": [
+		"'self'",
+		"data:",
+		"https://pbs.twimg.com",
+		"https://abs.twimg.com",
+		"https://syndication.twitter.com",
+		"https://platform.twitter.com",
+		"https://www.google-analytics.com",
+		"https://www.googletagmanager.com",
+	],
+	"frame-src": [
+		"https://platform.twitter.com",
+		"https://syndication.twitter.com",
+	],
+	"connect-src": [
+		"'self'",
+		"https://www.google-analytics.com",
+	],
+}
+
+CSP_HEADERS = {
+	"off": None,
+	"report-only": "Content-Security-Policy-Report-Only",
+	"enforce": "Content-Security-Policy",
+}
+
 
 @web_util.final_route
 @web_util.route(r"/(?P<path>[a-zA-Z0-9._-]+(/[a-zA-Z0-9._-]+)*)$")
@@ -31,6 +83,64 @@ async def static_file_handler(request, *, base_directory: pathlib.Path, path, va
 	return web.FileResponse(complete_path, chunk_size=1024 * 1024)
 
 
+def make_content_security_policy(index_html, report_uri=None):
+	'''
+	Build a content security policy for index.html. Its inline scripts are
+	allowed by hash, so the policy must be rebuilt if index.html changes. If
+	report_uri is given, browsers send violation reports there.
+	'''
+	sources = {directive: list(values) for directive, values in CSP_SOURCES.items()}
+
+	for script in INLINE_SCRIPT.findall(index_html):
+		digest = hashlib.sha256(script.encode('utf8')).digest()
+		sources["script-src"].append(
+			f"'sha256-{base64.b64encode(digest).decode('ascii')}'"
+		)
+
+	if report_uri is not None:
+		sources["report-uri"] = [report_uri]
+
+	return "; ".join(
+		f"{directive} {' '.join(values)}"
+		for directive, values in sources.items()
+	)
+
+
+def make_html_headers(index_path, *, csp_mode, csp_report_uri=None):
+	headers = {
+		"X-Content-Type-Options": "nosniff",
+		"Referrer-Policy": "strict-origin-when-cross-origin",
+	}
+
+	csp_header = CSP_HEADERS[csp_mode]
+	if csp_header is not None:
+		headers[csp_header] = make_content_security_policy(
+			index_path.read_text(),
+			report_uri=csp_report_uri,
+		)
+
+	return headers
+
+
+@web_util.method_handler('GET', 'HEAD')
+async def index_handler(request, index_path, html_headers):
+	return web.FileResponse(index_path, headers=html_headers)
+
+
+def make_robots_txt(*, crawl_threads):
+	'''
+	Build the robots.txt content. By default, crawlers are kept away from
+	thread pages, since crawling them generates twitter API load.
+	'''
+	lines = ["User-agent: *", "Disallow: /api/"]
+
+	if not crawl_threads:
+		lines.append("Disallow: /thread/")
+
+	lines.append("Allow: /")
+	return "\n".join(lines) + "\n"
+
+
 @web_util.method_handler('GET', 'HEAD')
-async def index_handler(request, index_path):
-	return web.FileResponse(index_path, )
+async def robots_handler(request, *, robots_txt):
+	return web.Response(text=robots_txt, content_type='text/plain')
@@ -1,31 +1,24 @@
+import asyncio
 import os
 import pathlib
+import ssl
 
 from aiohttp import web
 from autocommand import autocommand
 import aiohttp
-import cachetools
 
-from bobbin import twitter, tweetbox, async_cache, api_server, web_util, frontend_server
-
-
-class AsyncLRUCache(async_cache.Cache):
-	def __init__(self, max_size):
-		self.cache = cachetools.LRUCache(max_size, getsizeof=async_cache.get_size_of)
-
-	async def get(self, key):
-		with self.convert_keyerror():
-			return self.cache[key]
-
-	async def write(self, key, value):
-		self.cache[key] = value
+from bobbin import (
+	twitter, tweetbox, async_cache, api_server, web_util, frontend_server,
+	error_reporting, settings, serialization,
+)
 
 
 main_handler = web_util.routes(
-	(r'/$', frontend_server.index_handler, 'index_path'),
-	(r'/thread/[0-9]{1,21}/?$', frontend_server.index_handler, 'index_path'),
-	(r'/faq/?$', frontend_server.index_handler, 'index_path'),
-	(r'/api/', api_server.handler, 'get_thread'),
+	(r'/$', frontend_server.index_handler, ['index_path', 'html_headers']),
+	(r'/thread/[0-9]{1,21}(/[0-9]{1,21})?/?$', frontend_server.index_handler, ['index_path', 'html_headers']),
+	(r'/faq/?$', frontend_server.index_handler, ['index_path', 'html_headers']),
+	(r'/robots\.txt$', frontend_server.robots_handler, 'robots_txt'),
+	(r'/api/', api_server.handler, ['builds', 'build_budget', 'thread_etags', 'prewarm', 'debug_headers', 'collapse_after']),
 	(r'/static/', frontend_server.static_file_handler, ['base_directory', 'valid_paths']),
 )
 
@@ -38,29 +31,140 @@ def walk_dir(path):
 			yield from walk_dir(child)
 
 
-def parse_size(size):
-	if size.endswith("KB"):
-		return int(size[:-2]) * 1024
-	elif size.endswith("MB"):
-		return int(size[:-2]) * 1024 * 1024
-	elif size.endswith("GB"):
-		return int(size[:-2]) * 1024 * 1024 * 1024
-	elif size.endswith("B"):
-		return int(size[:-1])
-	else:
-		return int(size)
+async def reload_credentials(config, token, *, interval, reload_key, reload_secret, report_error):
+	'''
+	Poll the --config file, and switch the token to new credentials when the
+	file's CONSUMER_KEY or CONSUMER_SECRET changes. Only the --config file is
+	watched: credentials given with --key and --secret, or with the
+	CONSUMER_KEY and CONSUMER_SECRET environment variables, are fixed for the
+	life of the process, and take precedence over the file's, so rotating
+	those requires a restart.
+	'''
+	def modified_time():
+		try:
+			return config.stat().st_mtime
+		except OSError:
+			return None
+
+	last_modified = modified_time()
+
+	while True:
+		await asyncio.sleep(interval)
+
+		modified = modified_time()
+		if modified is None or modified == last_modified:
+			continue
+
+		last_modified = modified
+
+		try:
+			file_config = settings.read_config_file(config)
+		except (OSError, settings.SettingsError) as e:
+			error_reporting.safe_report(report_error, e, {"task": "credential reload"})
+			continue
+
+		key = file_config.get("CONSUMER_KEY") if reload_key else token.consumer_key
+		secret = file_config.get("CONSUMER_SECRET") if reload_secret else token.consumer_secret
+
+		if key is None or secret is None:
+			continue
+
+		if (key, secret) != (token.consumer_key, token.consumer_secret):
+			token.set_credentials(key, secret)
+
+			try:
+				await token.regenerate()
+			except twitter.TokenError as e:
+				error_reporting.safe_report(report_error, e, {"task": "credential reload"})
 
 
 @autocommand(__name__, loop=True, pass_loop=True)
 async def main(
 	key: str =os.environ.get("CONSUMER_KEY", None),
 	secret: str =os.environ.get("CONSUMER_SECRET", None),
-	host="0.0.0.0",
-	port=8080,
+	host: str =None,
+	port: int =None,
+	tls_cert: pathlib.Path =None,
+	tls_key: pathlib.Path =None,
 	static_dir=pathlib.Path('./static'),
-	cache_size="256MB",
+	cache_size: str =None,
+	cache_format="pickle",
+	cache_min_thread_length=2,
+	cache_max_age: float =None,
+	prewarm_threads=0,
+	max_builds=32,
+	timeline_concurrency=1,
+	max_show_requests: int =None,
+	max_lookup_requests: int =None,
+	max_timeline_requests: int =None,
+	max_tweet_v2_requests: int =None,
+	max_search_requests: int =None,
+	token_timeout=10.0,
+	build_budget: float =None,
+	config: pathlib.Path =None,
+	config_reload_interval=30.0,
+	crawl_threads=False,
+	strict_threads=False,
+	background_writes=False,
+	api_v2=False,
+	prefetch_token=False,
+	debug_headers=False,
+	csp_mode="report-only",
+	csp_report_uri: str =None,
+	collapse_after: int =None,
+	error_sink: str =None,
 	loop=None,
 ):
+	try:
+		file_config = {} if config is None else settings.read_config_file(config)
+	except (OSError, settings.SettingsError) as e:
+		return f"Couldn't read --config: {e}"
+
+	# Credentials from the config file are reloaded when it changes, so that
+	# they can be rotated without a restart
+	reload_key = config is not None and key is None
+	reload_secret = config is not None and secret is None
+
+	try:
+		key, secret, host, port, cache_size = settings.merge_config(
+			file_config,
+			key=key,
+			secret=secret,
+			host=host,
+			port=port,
+			cache_size=cache_size,
+		)
+
+		server_settings = settings.ServerSettings(
+			host=host,
+			port=port,
+			csp_mode=csp_mode,
+			csp_report_uri=csp_report_uri,
+			tls_cert=tls_cert,
+			tls_key=tls_key,
+			collapse_after=collapse_after,
+		).validate()
+		cache_settings = settings.CacheSettings(
+			size=settings.parse_size(cache_size),
+			format=cache_format,
+			min_thread_length=cache_min_thread_length,
+			max_age=cache_max_age,
+			prewarm_threads=prewarm_threads,
+		).validate()
+		limits = settings.LimitSettings(
+			max_builds=max_builds,
+			timeline_concurrency=timeline_concurrency,
+			max_show_requests=max_show_requests,
+			max_lookup_requests=max_lookup_requests,
+			max_timeline_requests=max_timeline_requests,
+			max_tweet_v2_requests=max_tweet_v2_requests,
+			max_search_requests=max_search_requests,
+			token_timeout=token_timeout,
+			build_budget=build_budget,
+		).validate()
+	except settings.SettingsError as e:
+		return f"Invalid settings: {e}"
+
 	if key is None:
 		return "Missing CONSUMER_KEY or --key"
 
@@ -71,26 +175,112 @@ async def main(
 	if not static_dir.is_dir():
 		return "--static_dir must be a directory"
 
-	cache = AsyncLRUCache(max_size=parse_size(cache_size))
+	try:
+		ssl_context = server_settings.make_ssl_context()
+	except (OSError, ssl.SSLError) as e:
+		return f"Couldn't load --tls_cert or --tls_key: {e}"
+
+	if error_sink is None:
+		report_error = error_reporting.ignore_error
+	else:
+		try:
+			report_error = error_reporting.load_sink(error_sink)
+		except (ImportError, AttributeError, ValueError) as e:
+			return f"Couldn't load --error_sink: {e}"
+
+	error_reporting.install_loop_sink(loop, report_error)
+
+	# Without a max age, cached tweets are kept until they're evicted
+	cache = async_cache.AsyncLRUCache(
+		max_size=cache_settings.size,
+		ttl=cache_settings.max_age,
+	)
 
 	async with aiohttp.ClientSession() as session:
-		token = twitter.Token(session, key, secret)
+		token = twitter.Token(session, key, secret, timeout=limits.token_timeout)
 
 		get_thread = tweetbox.make_thread_getter(
 			session=session,
 			cache=cache,
-			token=token
+			token=token,
+			error_sink=report_error,
+			allow_missing=not strict_threads,
+			codec=serialization.CODECS[cache_settings.format],
+			min_cache_length=cache_settings.min_thread_length,
+			timeline_concurrency=limits.timeline_concurrency,
+			await_writes=not background_writes,
+			endpoint_concurrency={
+				twitter.Endpoint.show: limits.max_show_requests,
+				twitter.Endpoint.lookup: limits.max_lookup_requests,
+				twitter.Endpoint.user_timeline: limits.max_timeline_requests,
+				twitter.Endpoint.tweet_v2: limits.max_tweet_v2_requests,
+				twitter.Endpoint.search_recent: limits.max_search_requests,
+			},
+			api_v2=api_v2,
+		)
+
+		builds = tweetbox.ThreadBuilds(get_thread, limits.max_builds, error_sink=report_error)
+
+		# Recently requested threads are rebuilt twice per max age, so their
+		# tweets are refreshed before they expire
+		prewarm = None if cache_settings.prewarm_threads == 0 else tweetbox.PrewarmQueue(
+			builds,
+			max_size=cache_settings.prewarm_threads,
+			interval=cache_settings.max_age / 2,
+			error_sink=report_error,
 		)
 
 		handler = web_util.with_context(
-			web_util.shitty_logging(main_handler),
-			get_thread=get_thread,
+			web_util.shitty_logging(web_util.report_errors(report_error, main_handler)),
+			builds=builds,
+			prewarm=prewarm,
+			build_budget=limits.build_budget,
+			thread_etags=async_cache.AsyncLRUCache(
+				max_size=api_server.ETAG_CACHE_SIZE,
+				ttl=cache_settings.max_age,
+			),
+			debug_headers=debug_headers,
+			collapse_after=server_settings.collapse_after,
 			base_directory=static_dir,
 			valid_paths=None,
-			index_path=static_dir / 'index.html'
+			index_path=static_dir / 'index.html',
+			html_headers=frontend_server.make_html_headers(
+				static_dir / 'index.html',
+				csp_mode=server_settings.csp_mode,
+				csp_report_uri=server_settings.csp_report_uri,
+			),
+			robots_txt=frontend_server.make_robots_txt(crawl_threads=crawl_threads),
 		)
 
+		if prefetch_token:
+			# Get the bearer token in the background while the server starts.
+			# Static pages don't need it, and thread requests that arrive
+			# before it's ready share the same in-flight token request.
+			error_reporting.report_task_errors(
+				asyncio.ensure_future(token.get_token()),
+				report_error,
+				{"task": "token warmup"},
+			)
+
+		if prewarm is not None:
+			asyncio.ensure_future(prewarm.run())
+
+		if (reload_key or reload_secret) and config_reload_interval > 0:
+			asyncio.ensure_future(reload_credentials(
+				config,
+				token,
+				interval=config_reload_interval,
+				reload_key=reload_key,
+				reload_secret=reload_secret,
+				report_error=report_error,
+			))
+
 		http_server = web.Server(handler, loop=loop)
-		server = await loop.create_server(http_server, host, port)
+		server = await loop.create_server(
+			http_server,
+			server_settings.host,
+			server_settings.port,
+			ssl=ssl_context,
+		)
 
 		await server.wait_closed()
@@ -0,0 +1,64 @@
+# Codecs for storing tweets in the cache. Every blob starts with a prefix
+# identifying the codec (and its format version) that wrote it, so a blob
+# written in a different format is rejected rather than misread. tweetbox
+# treats undecodable blobs as cache misses, so changing codecs just means
+# refetching old entries.
+
+import json
+import pickle
+
+from bobbin.twitter import Tweet, TwitterUser
+
+
+class CodecError(ValueError):
+	pass
+
+
+class Codec:
+	prefix = b''
+
+	def encode(self, tweet):
+		return self.prefix + self.encode_body(tweet)
+
+	def decode(self, blob):
+		if not blob.startswith(self.prefix):
+			raise CodecError("Blob was written by a different codec")
+		return self.decode_body(blob[len(self.prefix):])
+
+	def encode_body(self, tweet):
+		raise NotImplementedError()
+
+	def decode_body(self, body):
+		raise NotImplementedError()
+
+
+class PickleCodec(Codec):
+	prefix = b'p1:'
+
+	def encode_body(self, tweet):
+		return pickle.dumps(tweet, protocol=4)
+
+	def decode_body(self, body):
+		return pickle.loads(body)
+
+
+class JsonCodec(Codec):
+	prefix = b'j1:'
+
+	def encode_body(self, tweet):
+		return json.dumps(
+			tweet._replace(user=tweet.user._asdict())._asdict(),
+			separators=(',', ':'),
+		).encode('utf8')
+
+	def decode_body(self, body):
+		fields = json.loads(body)
+		user = fields['user']
+		fields['user'] = TwitterUser(*(user[field] for field in TwitterUser._fields))
+		return Tweet(*(fields[field] for field in Tweet._fields))
+
+
+CODECS = {
+	"pickle": PickleCodec(),
+	"json": JsonCodec(),
+}
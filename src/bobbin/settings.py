@@ -0,0 +1,167 @@
+# Grouped server settings. main assembles these from the command line, the
+# environment, and the --config file, and validates each group at startup, so
+# that nonsensical values are rejected with a clear message before the server
+# starts rather than causing strange behavior later.
+
+import ssl
+from collections import namedtuple
+
+from bobbin import serialization
+
+
+class SettingsError(ValueError):
+	pass
+
+
+def parse_size(size):
+	try:
+		if size.endswith("KB"):
+			return int(size[:-2]) * 1024
+		elif size.endswith("MB"):
+			return int(size[:-2]) * 1024 * 1024
+		elif size.endswith("GB"):
+			return int(size[:-2]) * 1024 * 1024 * 1024
+		elif size.endswith("B"):
+			return int(size[:-1])
+		else:
+			return int(size)
+	except ValueError as e:
+		raise SettingsError(f"Invalid size: {size!r}") from e
+
+
+def parse_int(value, name):
+	try:
+		return int(value)
+	except ValueError as e:
+		raise SettingsError(f"{name} must be an integer, got {value!r}") from e
+
+
+def read_config_file(path):
+	'''
+	Read a simple config file of KEY=VALUE lines, in the style of a .env file.
+	Blank lines and lines starting with # are ignored. Values given on the
+	command line or in the environment take precedence over values from this
+	file.
+	'''
+	config = {}
+
+	with path.open() as config_file:
+		for line_number, line in enumerate(config_file, 1):
+			line = line.strip()
+			if not line or line.startswith('#'):
+				continue
+
+			name, sep, value = line.partition('=')
+			if not sep:
+				raise SettingsError(f"{path}:{line_number}: expected KEY=VALUE")
+
+			config[name.strip()] = value.strip()
+
+	return config
+
+
+# The settings that can also be given in the --config file
+ConfigValues = namedtuple("ConfigValues", "key secret host port cache_size")
+
+
+def merge_config(file_config, *, key, secret, host, port, cache_size):
+	'''
+	Fill in the settings that weren't given on the command line or in the
+	environment (which are None) from the --config file's values, or from
+	their defaults. Returns ConfigValues; key and secret are None if they
+	weren't given anywhere.
+	'''
+	return ConfigValues(
+		key=key if key is not None else file_config.get("CONSUMER_KEY"),
+		secret=secret if secret is not None else file_config.get("CONSUMER_SECRET"),
+		host=host if host is not None else file_config.get("HOST", "0.0.0.0"),
+		port=port if port is not None else parse_int(file_config.get("PORT", "8080"), "PORT"),
+		cache_size=cache_size if cache_size is not None else file_config.get("CACHE_SIZE", "256MB"),
+	)
+
+
+CSP_MODES = ("off", "report-only", "enforce")
+
+
+class ServerSettings(namedtuple("ServerSettings", "host port csp_mode csp_report_uri tls_cert tls_key collapse_after")):
+	__slots__ = ()
+
+	def validate(self):
+		if not 1 <= self.port <= 65535:
+			raise SettingsError(f"port must be between 1 and 65535, got {self.port}")
+		if (self.tls_cert is None) != (self.tls_key is None):
+			raise SettingsError("tls_cert and tls_key must be given together")
+		if self.collapse_after is not None and self.collapse_after < 1:
+			raise SettingsError("collapse_after must be at least 1")
+		if self.csp_mode not in CSP_MODES:
+			raise SettingsError(
+				f"csp mode must be one of {', '.join(CSP_MODES)}, got {self.csp_mode!r}"
+			)
+		if self.csp_report_uri is not None and (
+			not self.csp_report_uri or
+			any(char.isspace() or char in ";," for char in self.csp_report_uri)
+		):
+			raise SettingsError(f"invalid csp_report_uri {self.csp_report_uri!r}")
+		return self
+
+	def make_ssl_context(self):
+		'''
+		Create an SSL context for serving HTTPS, or None to serve plain HTTP
+		'''
+		if self.tls_cert is None:
+			return None
+
+		context = ssl.create_default_context(ssl.Purpose.CLIENT_AUTH)
+		context.load_cert_chain(self.tls_cert, self.tls_key)
+		return context
+
+
+class CacheSettings(namedtuple("CacheSettings", "size format min_thread_length max_age prewarm_threads")):
+	__slots__ = ()
+
+	def validate(self):
+		if self.size <= 0:
+			raise SettingsError("cache size must be greater than 0")
+		if self.min_thread_length < 1:
+			raise SettingsError("cache min_thread_length must be at least 1")
+		if self.max_age is not None and self.max_age <= 0:
+			raise SettingsError("cache max_age must be greater than 0")
+		if self.prewarm_threads < 0:
+			raise SettingsError("prewarm_threads must be at least 0")
+		if self.prewarm_threads > 0 and self.max_age is None:
+			raise SettingsError("prewarm_threads needs a cache max_age")
+		if self.format not in serialization.CODECS:
+			raise SettingsError(
+				f"cache format must be one of {', '.join(serialization.CODECS)}, "
+				f"got {self.format!r}"
+			)
+		return self
+
+
+class LimitSettings(namedtuple("LimitSettings", (
+	"max_builds timeline_concurrency max_show_requests max_lookup_requests "
+	"max_timeline_requests max_tweet_v2_requests max_search_requests "
+	"token_timeout build_budget"
+))):
+	__slots__ = ()
+
+	def validate(self):
+		if self.max_builds < 1:
+			raise SettingsError("max_builds must be at least 1")
+		if self.timeline_concurrency < 1:
+			raise SettingsError("timeline_concurrency must be at least 1")
+		for name in (
+			"max_show_requests",
+			"max_lookup_requests",
+			"max_timeline_requests",
+			"max_tweet_v2_requests",
+			"max_search_requests",
+		):
+			limit = getattr(self, name)
+			if limit is not None and limit < 1:
+				raise SettingsError(f"{name} must be at least 1")
+		if self.token_timeout <= 0:
+			raise SettingsError("token_timeout must be greater than 0")
+		if self.build_budget is not None and self.build_budget <= 0:
+			raise SettingsError("build_budget must be greater than 0")
+		return self
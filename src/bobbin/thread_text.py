@@ -0,0 +1,88 @@
+# Plain text and markdown renderings of a thread, for users who want to copy
+# a whole thread as text rather than view it as embedded tweets.
+
+import re
+
+MARKDOWN_SPECIAL = re.compile(r"([\\`*_\[\]<>#|~])")
+
+# Links in tweet text, which are made into markdown autolinks rather than
+# escaped, since escaping would change the URL. Trailing punctuation is
+# assumed to be part of the sentence rather than the link.
+URL = re.compile(r"(https?://[^\s<>]*[^\s<>.,;:!?'\")\]])")
+
+# Characters that start a list or an image when they begin a line, which
+# MARKDOWN_SPECIAL doesn't cover. This catches a few harmless cases too, like
+# "2+2", but markdown allows any punctuation to be escaped.
+BLOCK_MARKER = re.compile(r"^(\s*\d*)([-+!.)])")
+
+TRUNCATED_MESSAGE = "Earlier tweets in this thread are unavailable"
+
+
+def tweet_url(tweet):
+	return f"https://twitter.com/{tweet.user.handle}/status/{tweet.id}"
+
+
+def thread_title(thread, author):
+	'''
+	The same title the thread page shows: "Conversation" if the thread has no
+	single author, and otherwise "Thread by", or "Tweet by" for a lone tweet.
+	'''
+	if author is None:
+		return "Conversation"
+
+	kind = "Tweet" if len(thread.tweets) == 1 else "Thread"
+	return f"{kind} by @{author.handle}"
+
+
+def escape_markdown(text):
+	'''
+	Escape characters that markdown would otherwise interpret inline
+	'''
+	return MARKDOWN_SPECIAL.sub(r"\\\1", text)
+
+
+def escape_markdown_line(line):
+	'''
+	Escape a line of tweet text, including anything at its start that would
+	make it a block, but leave links as autolinks
+	'''
+	line = "".join(
+		f"<{part}>" if i % 2 else escape_markdown(part)
+		for i, part in enumerate(URL.split(line))
+	)
+	return BLOCK_MARKER.sub(r"\1\\\2", line)
+
+
+def escape_tweet_markdown(text):
+	'''
+	Escape tweet text for markdown, preserving its line breaks as markdown
+	hard breaks
+	'''
+	return "  \n".join(escape_markdown_line(line) for line in text.split("\n"))
+
+
+def render_text(thread, author):
+	parts = [thread_title(thread, author)]
+
+	if thread.truncated:
+		parts.append(f"[{TRUNCATED_MESSAGE}]")
+
+	for tweet in thread.tweets:
+		parts.append(f"{tweet.user.name} (@{tweet.user.handle}):\n{tweet.text}")
+
+	return "\n\n".join(parts) + "\n"
+
+
+def render_markdown(thread, author):
+	parts = [f"# {escape_markdown(thread_title(thread, author))}"]
+
+	if thread.truncated:
+		parts.append(f"_{TRUNCATED_MESSAGE}_")
+
+	for tweet in thread.tweets:
+		name = escape_markdown(tweet.user.name)
+		handle = escape_markdown(tweet.user.handle)
+		parts.append(f"**{name}** ([@{handle}]({tweet_url(tweet)}))")
+		parts.append(escape_tweet_markdown(tweet.text))
+
+	return "\n\n".join(parts) + "\n"
@@ -1,14 +1,35 @@
-from pickle import dumps as pickle_dump, loads as pickle_load
+import asyncio
+import contextlib
+from collections import Counter, OrderedDict, namedtuple
+from urllib.parse import urlencode
+
+import aiohttp
 
 from bobbin.async_cache import KeyNotFound, Cache as TweetCache
-from bobbin.async_util import shared_concurrent
-from bobbin.twitter import get_tweet, get_user_tweets, Tweet
+from bobbin.async_util import KeyedSemaphore, make_key, shared_concurrent
+from bobbin.error_reporting import ignore_error, report_task_errors, safe_report
+from bobbin.serialization import PickleCodec
+from bobbin.twitter import (
+	Endpoint, get_conversation, get_tweet, get_tweet_v2, get_user_tweets,
+	lookup_tweet, RateLimitError, Tweet, TwitterError, TwitterIDError,
+)
 from bobbin.task_manager import TaskWaiter
 
 # This is the primary interface where the logic lives. It handles caching and
 # the algorithmic decisions of which APIs to use
 
 
+# Timeline lookups are speculative: we fetch a batch of a user's tweets in the
+# hope that the rest of the thread is among them. If, after a few lookups,
+# hardly any of the fetched tweets turned out to be part of the thread, we stop
+# doing timeline lookups for the rest of the build.
+PREFETCH_SAMPLE_SIZE = 2
+PREFETCH_MIN_HIT_RATE = 0.05
+
+# Used to estimate how long a thread takes to read
+WORDS_PER_MINUTE = 200
+
+
 class InvalidThreadError(Exception):
 	pass
 
@@ -17,7 +38,119 @@ class MismatchedHeadError(InvalidThreadError):
 	pass
 
 
-async def generate_thread(*, session, cache: TweetCache, token, tail, head=None):
+class MissingAncestorError(InvalidThreadError):
+	'''
+	Raised when one of the tail's ancestors can't be retrieved, and the thread
+	can't be truncated there. The underlying TwitterIDError is the __cause__.
+	'''
+	pass
+
+
+class BuildLimitError(Exception):
+	'''
+	Raised by ThreadBuilds when a new build is needed, but every build slot is
+	taken
+	'''
+	pass
+
+
+# Yielded by generate_thread, as its final item, when the walk reaches a tweet
+# that can't be retrieved (usually because it was deleted). This means the
+# thread continues above, but its earlier tweets are unavailable.
+MissingTweet = namedtuple("MissingTweet", "id")
+
+
+# author is the user the thread is attributed to, or None if it's a
+# conversation. leader is the user with the most tweets, or None if there's a
+# tie for the most; share is the fraction of the thread's tweets by the leader.
+ThreadAuthorship = namedtuple("ThreadAuthorship", "author leader share participants")
+
+
+class AuthorCounter:
+	'''
+	A running tally of who wrote a thread's tweets, which can be updated one
+	tweet at a time as the thread is built.
+	'''
+	def __init__(self):
+		self.user_counts = Counter()
+		self.total = 0
+
+	def add(self, tweet):
+		self.user_counts[tweet.user] += 1
+		self.total += 1
+
+	def classify(self):
+		user_counts = self.user_counts
+
+		if len(user_counts) == 0:
+			return ThreadAuthorship(None, None, 0.0, 0)
+
+		top_users = user_counts.most_common(2)
+		leader, leader_count = top_users[0]
+		share = leader_count / self.total
+
+		if len(user_counts) == 1:
+			return ThreadAuthorship(leader, leader, share, 1)
+
+		if leader_count == top_users[1][1]:
+			return ThreadAuthorship(None, None, share, len(user_counts))
+
+		author = leader if leader_count * 2 >= len(user_counts) else None
+		return ThreadAuthorship(author, leader, share, len(user_counts))
+
+
+class Thread(namedtuple("Thread", "tweets truncated stats sources head context author_only authors")):
+	'''
+	A thread's tweets, in order from head to tail. truncated is true if the
+	walk stopped at a missing tweet rather than at the real head of the thread.
+	stats is a Counter of how the thread was built: cache_hits and
+	cache_misses count tweets found and not found in the cache, and api_calls
+	counts requests made to twitter. sources maps each tweet's ID to where it
+	was found: "cache", "timeline" (prefetched from a timeline lookup),
+	"conversation" (prefetched from a conversation search), or "api". head,
+	context, and author_only are the options the thread was requested with.
+	authors is an AuthorCounter of the thread's tweets.
+	'''
+	__slots__ = ()
+
+	def share_path(self):
+		'''
+		The canonical path of this thread's page. A thread is identified by its
+		tail, plus its head if it was requested with one. The other options
+		that change which tweets are shown go in the query string.
+		'''
+		tail = self.tweets[-1].id
+		path = f"/thread/{tail}" if self.head is None else f"/thread/{tail}/{self.head}"
+
+		query = {}
+		if self.context is not None:
+			query["context"] = self.context
+		if self.author_only:
+			query["author_only"] = 1
+
+		return f"{path}?{urlencode(query)}" if query else path
+
+	def authorship(self):
+		return self.authors.classify()
+
+	def tweet_count(self):
+		return len(self.tweets)
+
+	def reading_minutes(self):
+		'''
+		Roughly how many minutes it takes to read the thread; always at least 1
+		'''
+		words = sum(len(tweet.text.split()) for tweet in self.tweets)
+		return max(1, round(words / WORDS_PER_MINUTE))
+
+
+async def generate_thread(
+	*, session, cache: TweetCache, token, tail, head=None, context=None,
+	error_sink=ignore_error, allow_missing=True, codec=PickleCodec(),
+	stats=None, sources=None, min_cache_length=1, timeline_slots=None,
+	background_writes=None, endpoint_slots=None, author_only=False,
+	api_v2=False, authors=None, refresh=False,
+):
 	'''
 	Get a list a tweet IDs comprising a thread, in order from tail to
 	head.
@@ -32,37 +165,186 @@ async def generate_thread(*, session, cache: TweetCache, token, tail, head=None)
 	Threads are yielded, but if the head tweet is never found, an exception is
 	rasied.
 
-	Cache should have async "get" and "write" methods.
+	If context is given, at most that many tweets before the tail are
+	yielded, regardless of head.
+
+	If author_only is true, the walk stops before the first tweet that isn't
+	by the tail's author, so only the author's own chain of self-replies is
+	yielded.
+
+	If one of the tail's ancestors can't be retrieved, a MissingTweet is
+	yielded and the walk ends, unless allow_missing is false, in which case
+	MissingAncestorError is raised. If the tail itself can't be retrieved,
+	the TwitterIDError is raised.
+
+	Cache should have async "get" and "write" methods. Tweets are stored in
+	the cache with codec. Nothing is written to the cache unless the thread
+	has at least min_cache_length tweets, since very short threads are cheap
+	to rebuild. Cache writes are normally finished before the generator is;
+	if background_writes is given, it should be a TaskWaiter, and writes are
+	added to it instead, so they can outlive the build. If refresh is true,
+	the cache isn't read, so every tweet is fetched from twitter again and
+	rewritten to the cache, which restarts its max age.
+
+	Errors that are recovered from, like corrupt cache entries, are reported
+	to error_sink.
+
+	If stats is given, it should be a Counter, which is updated as described
+	in Thread, and likewise for sources, which should be a dict. If authors
+	is given, it should be an AuthorCounter; it's updated as each tweet is
+	yielded.
+
+	If timeline_slots is given, it should be a KeyedSemaphore, which limits
+	concurrent timeline lookups of each user. If endpoint_slots is given, it
+	should map Endpoints to semaphores, which limit concurrent requests to
+	those endpoints.
+
+	If api_v2 is true, individual tweets are looked up with the v2 API, which
+	includes their conversation_id, and the rest of each conversation is
+	prefetched with a search; timeline lookups always use v1.1. Otherwise,
+	they're looked up with statuses/show, or statuses/lookup while
+	statuses/show is rate limited.
 	'''
 
-	# local_store is where tweets pulled from the API live. Tweets retreived
-	# from this store (via get_cached_tweet) are stored in the cache
+	# local_store is where tweets pulled from the API live, along with where
+	# they came from ("timeline" or "conversation"). Tweets retreived from
+	# this store (via get_cached_tweet) are stored in the cache
 	local_store = {}
 	writers = TaskWaiter()
+	write_manager = writers if background_writes is None else background_writes
+
+	# Users whose timeline lookup came back empty. This almost always means
+	# the thread is older than the ~3,200 tweets the timeline API can reach,
+	# so further timeline lookups for these users would be wasted API calls.
+	exhausted_timelines = set()
+
+	# Conversations we've already searched for (in api_v2 mode)
+	searched_conversations = set()
+
+	# Tracks how many timeline lookups we've done, how many tweets they
+	# fetched, and how many of those tweets were actually used.
+	prefetch_stats = Counter()
+
+	if stats is None:
+		stats = Counter()
+
+	if sources is None:
+		sources = {}
+
+	if timeline_slots is None:
+		timeline_slots = KeyedSemaphore(1)
+
+	if endpoint_slots is None:
+		endpoint_slots = {}
+
+	@contextlib.asynccontextmanager
+	async def endpoint_slot(endpoint):
+		slot = endpoint_slots.get(endpoint)
+		if slot is None:
+			yield
+		else:
+			async with slot:
+				yield
+
+	def prefetch_is_useful():
+		if prefetch_stats['lookups'] < PREFETCH_SAMPLE_SIZE:
+			return True
+
+		hit_rate = prefetch_stats['hits'] / max(prefetch_stats['fetched'], 1)
+		return hit_rate >= PREFETCH_MIN_HIT_RATE
+
+	# Tweets waiting to be cached until the thread is long enough to be worth
+	# caching. Once it is, this is set to None and tweets are written as
+	# they're found.
+	pending_writes = []
 
 	def store_tweet_bg(tweet_id, tweet: Tweet):
 		'''
 		Given a tweet ID and a parent ID, schedule the parent-child
 		to be stored in the background.
 		'''
-		writers.add_task(cache.write(tweet_id, pickle_dump(tweet, protocol=4)))
+		if pending_writes is None:
+			write_manager.add_task(write_tweet(tweet_id, tweet))
+		else:
+			pending_writes.append((tweet_id, tweet))
+
+	async def write_tweet(tweet_id, tweet):
+		try:
+			await cache.write(tweet_id, codec.encode(tweet))
+		except Exception as e:
+			safe_report(error_sink, e, {"cache_key": tweet_id})
+
+	def flush_pending_writes():
+		nonlocal pending_writes
+		writes = pending_writes
+		pending_writes = None
+
+		for tweet_id, tweet in writes:
+			store_tweet_bg(tweet_id, tweet)
 
 	async def get_cached_tweet(tweet_id):
 		'''
 		Returns a parent id, or None, or raise an exception, from the cache.
-		Checks the local_store, which is tweets found from a user_timeline
-		lookup. If not found, check the cache,
+		Checks the cache first, then the local_store, which is tweets found
+		from a user_timeline lookup. Tweets never change, so a tweet that's
+		already in the cache is never written to it again, unless we're
+		refreshing it.
 		'''
-		# TODO: work out if it's better to check the real cache first
+		if not refresh:
+			try:
+				return await read_cache(tweet_id)
+			except KeyNotFound:
+				pass
+
 		try:
-			tweet = local_store[tweet_id]
+			tweet, source = local_store[tweet_id]
 		except KeyError:
-			pass
-		else:
-			store_tweet_bg(tweet_id, tweet)
-			return tweet
+			raise KeyNotFound(tweet_id)
+
+		if source == "timeline":
+			prefetch_stats['hits'] += 1
+		sources[tweet_id] = source
+		store_tweet_bg(tweet_id, tweet)
+		return tweet
+
+	async def read_cache(tweet_id):
+		try:
+			blob = await cache.get(tweet_id)
+		except KeyNotFound:
+			stats['cache_misses'] += 1
+			raise
+
+		# A blob that can't be decoded is treated as a cache miss rather than
+		# an error; the tweet is then looked up again, and the fresh copy
+		# overwrites the corrupt one. Decoding (especially unpickling) can
+		# raise nearly anything, so we can't be more specific about what we
+		# catch here.
+		try:
+			tweet = codec.decode(blob)
+		except Exception as e:
+			stats['cache_misses'] += 1
+			safe_report(error_sink, e, {"cache_key": tweet_id})
+			raise KeyNotFound(tweet_id) from e
+
+		stats['cache_hits'] += 1
+		sources[tweet_id] = "cache"
+		return tweet
 
-		return pickle_load(await cache.get(tweet_id))
+	async def fetch_tweet(tweet_id):
+		stats['api_calls'] += 1
+		if api_v2:
+			async with endpoint_slot(Endpoint.tweet_v2):
+				return await get_tweet_v2(session=session, token=token, tweet_id=tweet_id)
+
+		try:
+			async with endpoint_slot(Endpoint.show):
+				return await get_tweet(session=session, token=token, tweet_id=tweet_id)
+		except RateLimitError:
+			# statuses/lookup has its own rate limit, so it's often still
+			# available when statuses/show isn't
+			stats['api_calls'] += 1
+			async with endpoint_slot(Endpoint.lookup):
+				return await lookup_tweet(session=session, token=token, tweet_id=tweet_id)
 
 	async def load_tweets(tweet_id):
 		'''
@@ -79,60 +361,294 @@ async def generate_thread(*, session, cache: TweetCache, token, tail, head=None)
 		'''
 
 		# TODO: HANDLE ALL THE ERRORS
-		tweet = await get_tweet(session=session, token=token, tweet_id=tweet_id)
+		tweet = await fetch_tweet(tweet_id)
+		sources[tweet_id] = "api"
 		store_tweet_bg(tweet_id, tweet)
 
 		if tweet.parent_user_id is None:
 			return tweet
 
+		# With the v2 API, one search can find the whole conversation, but
+		# only if it's less than about a week old. Fall back on timeline
+		# lookups if it doesn't find this tweet's parent.
+		conversation_id = tweet.conversation_id
+		if conversation_id is not None and conversation_id not in searched_conversations:
+			searched_conversations.add(conversation_id)
+			conversation = {}
+
+			# Each page is a separate request. If one fails, we keep the
+			# pages we already have. The search is only an optimization, so
+			# even errors that would fail the build elsewhere, like the app
+			# not having search access, just mean falling back on timelines.
+			try:
+				async with endpoint_slot(Endpoint.search_recent):
+					async for page in get_conversation(
+						session=session,
+						token=token,
+						conversation_id=conversation_id,
+					):
+						stats['api_calls'] += 1
+						conversation.update(page)
+			except (TwitterError, aiohttp.ClientError, asyncio.TimeoutError) as e:
+				stats['api_calls'] += 1
+				safe_report(error_sink, e, {"conversation_id": conversation_id})
+
+			for conversation_tweet in conversation.values():
+				local_store[conversation_tweet.id] = (conversation_tweet, "conversation")
+
+			if tweet.parent_id in conversation:
+				return tweet
+
+		if tweet.parent_user_id in exhausted_timelines:
+			return tweet
+
+		# The walk won't go past a different author's tweet, so there's no
+		# point in fetching their timeline
+		if author_only and tweet.parent_user_id != tweet.user.id:
+			return tweet
+
+		if not prefetch_is_useful():
+			return tweet
+
 		# TODO: ignore most errors here
-		user_tweets = await get_user_tweets(
-			session=session,
-			token=token,
-			user_id=tweet.parent_user_id,
-			max_tweet=tweet_id,
-			count=100
-		)
+		stats['api_calls'] += 1
+		try:
+			async with timeline_slots.acquire(tweet.parent_user_id), endpoint_slot(Endpoint.user_timeline):
+				user_tweets = await get_user_tweets(
+					session=session,
+					token=token,
+					user_id=tweet.parent_user_id,
+					max_tweet=tweet_id,
+					count=100
+				)
+		except TwitterIDError:
+			# The parent's author is gone or suspended. The parent tweet
+			# itself will be reported missing when we try to look it up.
+			exhausted_timelines.add(tweet.parent_user_id)
+			return tweet
+
+		prefetch_stats['lookups'] += 1
+		prefetch_stats['fetched'] += len(user_tweets)
+
+		if not user_tweets:
+			exhausted_timelines.add(tweet.parent_user_id)
 
 		for user_tweet in user_tweets:
-			local_store[user_tweet.id] = user_tweet
+			local_store[user_tweet.id] = (user_tweet, "timeline")
 
 		return tweet
 
 	tweet_id = tail
+	ancestors = 0
 
 	with writers:
 		while tweet_id is not None:
 			try:
 				tweet = await get_cached_tweet(tweet_id)
 			except KeyNotFound:
-				tweet = await load_tweets(tweet_id)
+				try:
+					tweet = await load_tweets(tweet_id)
+				except TwitterIDError as e:
+					if tweet_id == tail:
+						raise
+					elif head is not None or not allow_missing:
+						raise MissingAncestorError(tweet_id) from e
 
+					yield MissingTweet(tweet_id)
+					break
+
+			if authors is not None:
+				authors.add(tweet)
 			yield tweet
 
+			if pending_writes is not None and ancestors + 1 >= min_cache_length:
+				flush_pending_writes()
+
 			if head is not None:
 				if tweet_id == head:
 					break
 				elif tweet.parent_id is None:
 					raise InvalidThreadError(head)
 
+			if context is not None and ancestors >= context:
+				break
+
+			if author_only and tweet.parent_user_id != tweet.user.id:
+				if head is not None:
+					raise InvalidThreadError(head)
+				break
+
+			ancestors += 1
 			tweet_id = tweet.parent_id
 
 		await writers.wait(instant=True)
 
 
-async def get_thread(*, session, cache, token, tail, head=None):
-	return list(reversed([tweet async for tweet in generate_thread(
+async def get_thread(
+	*, session, cache, token, tail, head=None, context=None,
+	error_sink=ignore_error, allow_missing=True, codec=PickleCodec(),
+	min_cache_length=1, timeline_slots=None, background_writes=None,
+	endpoint_slots=None, author_only=False, api_v2=False, refresh=False,
+):
+	stats = Counter()
+	sources = {}
+	authors = AuthorCounter()
+
+	tweets = [tweet async for tweet in generate_thread(
 		session=session,
 		cache=cache,
 		token=token,
 		tail=tail,
-		head=head
-	)]))
+		head=head,
+		context=context,
+		error_sink=error_sink,
+		allow_missing=allow_missing,
+		codec=codec,
+		stats=stats,
+		sources=sources,
+		min_cache_length=min_cache_length,
+		timeline_slots=timeline_slots,
+		background_writes=background_writes,
+		endpoint_slots=endpoint_slots,
+		author_only=author_only,
+		api_v2=api_v2,
+		authors=authors,
+		refresh=refresh,
+	)]
+
+	truncated = bool(tweets) and isinstance(tweets[-1], MissingTweet)
+	if truncated:
+		tweets.pop()
+
+	tweets.reverse()
+	return Thread(tweets, truncated, stats, sources, head, context, author_only, authors)
 
 
-def make_thread_getter(*, session, cache, token):
+def make_thread_getter(
+	*, session, cache, token, error_sink=ignore_error, allow_missing=True,
+	codec=PickleCodec(), min_cache_length=1, timeline_concurrency=1,
+	await_writes=True, endpoint_concurrency=None, api_v2=False,
+):
+	# Timelines are rate limited per user, so concurrent builds share a limit
+	# on how many lookups of any one user's timeline can run at once.
+	timeline_slots = KeyedSemaphore(timeline_concurrency)
+
+	# Unless they're awaited, cache writes are shared by all builds, so that
+	# they finish after the response is sent.
+	background_writes = None if await_writes else TaskWaiter()
+
+	# Limits on concurrent requests to each endpoint, across all builds
+	endpoint_slots = {
+		endpoint: asyncio.Semaphore(limit)
+		for endpoint, limit in (endpoint_concurrency or {}).items()
+		if limit is not None
+	}
+
 	@shared_concurrent
-	def local_get_thread(*, tail, head=None):
-		return get_thread(session=session, cache=cache, token=token, tail=tail, head=head)
+	def local_get_thread(*, tail, head=None, context=None, author_only=False, refresh=False):
+		return get_thread(
+			session=session,
+			cache=cache,
+			token=token,
+			tail=tail,
+			head=head,
+			context=context,
+			error_sink=error_sink,
+			allow_missing=allow_missing,
+			codec=codec,
+			min_cache_length=min_cache_length,
+			timeline_slots=timeline_slots,
+			background_writes=background_writes,
+			endpoint_slots=endpoint_slots,
+			author_only=author_only,
+			api_v2=api_v2,
+			refresh=refresh,
+		)
 	return local_get_thread
+
+
+class ThreadBuilds:
+	'''
+	Thread builds running in the background, at most max_builds at a time.
+	Builds are keyed by their get_thread arguments, so a request for a thread
+	that's already being built joins the existing build. A build holds its
+	slot until it finishes, even if every request waiting on it has given up.
+	Builds that fail are reported to error_sink, since there may be nobody
+	left to see the error.
+	'''
+	def __init__(self, get_thread, max_builds, *, error_sink=ignore_error):
+		self.get_thread = get_thread
+		self.max_builds = max_builds
+		self.error_sink = error_sink
+		self.running = {}
+
+	def start(self, **kwargs):
+		'''
+		Get the task building the thread for these get_thread arguments,
+		starting it if there isn't one. Raises BuildLimitError instead if
+		max_builds builds are already running.
+		'''
+		key = make_key(kwargs)
+		try:
+			return self.running[key]
+		except KeyError:
+			pass
+
+		if len(self.running) >= self.max_builds:
+			raise BuildLimitError()
+
+		task = self.running[key] = asyncio.ensure_future(self.get_thread(**kwargs))
+
+		task.add_done_callback(lambda task: self.running.pop(key))
+
+		# Nobody may be waiting for this build anymore, and the next request
+		# for the thread starts a new one, so this is the only place its
+		# error is sure to be seen.
+		return report_task_errors(task, self.error_sink, {"task": "thread build", **kwargs})
+
+
+class PrewarmQueue:
+	'''
+	The max_size most recently requested threads, which run() rebuilds in the
+	background every interval seconds, refreshing their tweets in the cache.
+	With a cache max age longer than interval, a popular thread's tweets are
+	refreshed before they expire, rather than being fetched again when it's
+	next requested. Rebuilds go through builds, one at a time, so they count
+	against its limit, and a thread is skipped if no slot is free.
+	'''
+	def __init__(self, builds, *, max_size, interval, error_sink=ignore_error):
+		self.builds = builds
+		self.max_size = max_size
+		self.interval = interval
+		self.error_sink = error_sink
+		self.threads = OrderedDict()
+
+	def add(self, **kwargs):
+		'''
+		Add a thread, by its get_thread arguments, as the most recently
+		requested. If the queue is full, the least recently requested thread
+		is dropped.
+		'''
+		key = make_key(kwargs)
+		self.threads[key] = kwargs
+		self.threads.move_to_end(key)
+
+		while len(self.threads) > self.max_size:
+			self.threads.popitem(last=False)
+
+	async def rebuild_all(self):
+		for key, kwargs in list(self.threads.items()):
+			try:
+				await self.builds.start(**kwargs, refresh=True)
+			except BuildLimitError:
+				pass
+			except (TwitterIDError, InvalidThreadError):
+				# The thread is gone, so there's nothing left to keep fresh
+				self.threads.pop(key, None)
+			except Exception as e:
+				safe_report(self.error_sink, e, {"task": "prewarm", **kwargs})
+
+	async def run(self):
+		while True:
+			await asyncio.sleep(self.interval)
+			await self.rebuild_all()
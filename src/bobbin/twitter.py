@@ -1,10 +1,18 @@
 # Low level async interface for twitter
 
+import asyncio
+import enum
+import json
+import re
+import time
 from base64 import b64encode
 from collections import namedtuple
 from functools import lru_cache
+from html import unescape as html_unescape
 from urllib.parse import quote as url_encode
 
+import aiohttp
+
 from bobbin import async_util
 
 BASE_API_URL = "https://api.twitter.com"
@@ -13,9 +21,49 @@ BASE_OAUTH_URL = f"{BASE_API_URL}/oauth2"
 TOKEN_URL = f"{BASE_OAUTH_URL}/token"
 RELEASE_URL = f"{BASE_OAUTH_URL}/invalidate_token"
 
+# Token responses are a few hundred bytes; anything much bigger is bogus
+MAX_TOKEN_RESPONSE_SIZE = 64 * 1024
+
 API_URL = f"{BASE_API_URL}/1.1"
 USER_TIMELINE_URL = f"{API_URL}/statuses/user_timeline"
 TWEET_URL = f"{API_URL}/statuses/show.json"
+TWEET_LOOKUP_URL = f"{API_URL}/statuses/lookup.json"
+
+API_V2_URL = f"{BASE_API_URL}/2"
+TWEET_V2_URL = f"{API_V2_URL}/tweets"
+SEARCH_RECENT_URL = f"{API_V2_URL}/tweets/search/recent"
+
+# Parameters for every v1.1 tweet lookup
+TWEET_PARAMS = {
+	"include_entities": "true",
+	"include_ext_alt_text": "false",
+	"tweet_mode": "extended",
+}
+
+# Fields requested for every v2 tweet, so they can be made into Tweets
+TWEET_V2_PARAMS = {
+	"tweet.fields": "author_id,conversation_id,in_reply_to_user_id,referenced_tweets",
+	"expansions": "author_id,in_reply_to_user_id",
+	"user.fields": "verified,verified_type",
+}
+
+# The most pages of search results get_conversation will fetch
+MAX_CONVERSATION_PAGES = 5
+
+# v2 tweets don't have a display_text_range, so we strip the leading
+# @mentions that twitter adds to replies ourselves
+LEADING_MENTIONS = re.compile(r"^(@\w+\s+)+")
+
+
+class Endpoint(enum.Enum):
+	'''
+	The API endpoints we use. Twitter rate limits each one separately.
+	'''
+	show = TWEET_URL
+	lookup = TWEET_LOOKUP_URL
+	user_timeline = USER_TIMELINE_URL
+	tweet_v2 = TWEET_V2_URL
+	search_recent = SEARCH_RECENT_URL
 
 
 class TwitterError(Exception):
@@ -23,7 +71,13 @@ class TwitterError(Exception):
 
 
 class RateLimitError(TwitterError):
-	pass
+	'''
+	Twitter is rate limiting us. retry_after is how many seconds until the
+	rate limit resets, if twitter said.
+	'''
+	def __init__(self, *args, retry_after=None):
+		super().__init__(*args)
+		self.retry_after = retry_after
 
 
 class TwitterIDError(TwitterError):
@@ -34,10 +88,120 @@ class NoSuchTweetError(TwitterIDError):
 	pass
 
 
+class DeletedTweetError(NoSuchTweetError):
+	'''
+	Twitter had a tweet with this ID, but it's gone, usually because it was
+	deleted. Other NoSuchTweetErrors may be for IDs that never existed.
+	'''
+	pass
+
+
+class ProtectedTweetError(NoSuchTweetError):
+	'''
+	The tweet exists, but its author's tweets are protected, so only their
+	followers can see it
+	'''
+	pass
+
+
 class NoSuchUserError(TwitterIDError):
 	pass
 
 
+class SuspendedUserError(NoSuchUserError):
+	pass
+
+
+class AuthenticationError(TwitterError):
+	pass
+
+
+class InvalidTokenError(AuthenticationError):
+	'''
+	Twitter rejected our bearer token as invalid or expired, so it should be
+	replaced
+	'''
+	pass
+
+
+class TokenError(AuthenticationError):
+	pass
+
+
+class TokenTimeoutError(TokenError):
+	pass
+
+
+# Twitter sometimes reports errors in the response body, with a 200 or 403
+# status, rather than (or as well as) with an HTTP error status. These are the
+# error codes we know how to interpret; see
+# https://developer.twitter.com/en/docs/basics/response-codes
+ERROR_CODES = {
+	32: AuthenticationError,
+	50: NoSuchUserError,
+	63: SuspendedUserError,
+	88: RateLimitError,
+	89: InvalidTokenError,
+	144: DeletedTweetError,
+	179: ProtectedTweetError,
+	215: InvalidTokenError,
+}
+
+
+def get_retry_after(headers):
+	'''
+	Get the number of seconds until the rate limit resets from the
+	x-rate-limit-reset header, which is a unix timestamp
+	'''
+	try:
+		reset = int(headers["x-rate-limit-reset"])
+	except (KeyError, ValueError):
+		return None
+
+	return max(reset - time.time(), 0)
+
+
+async def read_response(response, *, not_found=TwitterIDError):
+	'''
+	Read the JSON body of a twitter API response, raising an appropriate
+	TwitterError if the body contains a twitter error envelope. Code 34 ("Sorry,
+	that page does not exist") is endpoint specific, so the caller provides
+	the exception to raise for it.
+	'''
+	try:
+		result = await response.json(content_type=None)
+	except ValueError:
+		result = None
+
+	if isinstance(result, dict):
+		for error in result.get("errors", ()):
+			code = error.get("code")
+			message = error.get("message")
+
+			if code == 34:
+				raise not_found(code, message)
+
+			error_type = ERROR_CODES.get(code)
+			if error_type is RateLimitError:
+				raise RateLimitError(code, message, retry_after=get_retry_after(response.headers))
+			elif error_type is not None:
+				raise error_type(code, message)
+
+	if response.status == 401:
+		raise AuthenticationError(401, "Unauthorized")
+
+	# 420 ("Enhance Your Calm") is what older endpoints send instead of 429
+	if response.status in (420, 429):
+		raise RateLimitError(
+			response.status,
+			"Too many requests",
+			retry_after=get_retry_after(response.headers),
+		)
+
+	response.raise_for_status()
+	return result
+
+
 @lru_cache()
 def encode_twitter_key(*, consumer_key: str, consumer_secret: str):
 	return "Basic {code}".format(code=b64encode(
@@ -54,7 +218,7 @@ def encode_bearer_token(token):
 
 
 @async_util.shared_concurrent
-async def generate_bearer_token(*, session, consumer_key, consumer_secret):
+async def generate_bearer_token(*, session, consumer_key, consumer_secret, timeout=None):
 	headers = {
 		"Authorization": encode_twitter_key(
 			consumer_key=consumer_key,
@@ -64,65 +228,221 @@ async def generate_bearer_token(*, session, consumer_key, consumer_secret):
 		"Accept": "application/json",
 	}
 
-	async with session.post(
-		url=TOKEN_URL,
-		headers=headers,
-		data=b"grant_type=client_credentials",
-	) as response:
-		# TODO: handle errors better
-		response.raise_for_status()
-		result = await response.json()
-
-	if result['token_type'] != "bearer":
-		raise Exception('Token type wasn\'t "bearer"')
+	# Without an explicit timeout, fall back to the session's default
+	request_options = {} if timeout is None else {
+		"timeout": aiohttp.ClientTimeout(total=timeout),
+	}
 
-	return encode_bearer_token(result["access_token"])
+	try:
+		async with session.post(
+			url=TOKEN_URL,
+			headers=headers,
+			data=b"grant_type=client_credentials",
+			**request_options,
+		) as response:
+			response.raise_for_status()
+
+			# Content-Length is missing from chunked responses, so rather than
+			# trusting it, read at most one byte more than the limit
+			body = b""
+			while len(body) <= MAX_TOKEN_RESPONSE_SIZE:
+				chunk = await response.content.read(MAX_TOKEN_RESPONSE_SIZE + 1 - len(body))
+				if not chunk:
+					break
+				body += chunk
+
+			if len(body) > MAX_TOKEN_RESPONSE_SIZE:
+				raise TokenError("Token response from twitter was too large")
+	except asyncio.TimeoutError as e:
+		raise TokenTimeoutError(f"Token request timed out after {timeout} seconds") from e
+	except aiohttp.ClientError as e:
+		raise TokenError("Couldn't get a bearer token from twitter") from e
+
+	try:
+		result = json.loads(body)
+	except ValueError as e:
+		raise TokenError("Token response from twitter wasn't valid JSON") from e
+
+	if not isinstance(result, dict) or result.get('token_type') != "bearer":
+		raise TokenError('Token type wasn\'t "bearer"')
+
+	access_token = result.get("access_token")
+	if not isinstance(access_token, str):
+		raise TokenError("Token response from twitter had no access token")
+
+	return encode_bearer_token(access_token)
 
 
 class Token:
-	def __init__(self, session, consumer_key, consumer_secret):
+	def __init__(self, session, consumer_key, consumer_secret, timeout=None):
 		self.session = session
 		self.consumer_key = consumer_key
 		self.consumer_secret = consumer_secret
+		self.timeout = timeout
 		self.token = None
 
 	async def regenerate(self):
-		token = self.token = await generate_bearer_token(
+		consumer_key = self.consumer_key
+		consumer_secret = self.consumer_secret
+
+		token = await generate_bearer_token(
 			session=self.session,
-			consumer_key=self.consumer_key,
-			consumer_secret=self.consumer_secret,
+			consumer_key=consumer_key,
+			consumer_secret=consumer_secret,
+			timeout=self.timeout,
 		)
+
+		# Don't clobber a token for credentials that were swapped in while
+		# this one was being generated
+		if (consumer_key, consumer_secret) == (self.consumer_key, self.consumer_secret):
+			self.token = token
 		return token
 
+	def set_credentials(self, consumer_key, consumer_secret):
+		'''
+		Switch to new credentials. The current bearer token is discarded, and
+		a new one is generated the next time one is needed.
+		'''
+		self.consumer_key = consumer_key
+		self.consumer_secret = consumer_secret
+		self.token = None
+
 	async def get_token(self):
 		token = self.token
 		if token is None:
 			token = await self.regenerate()
 		return token
 
+	async def refresh(self, stale_token):
+		'''
+		Replace a token that twitter rejected. If the token has already been
+		replaced since stale_token was retrieved, the current one is used
+		instead, so a burst of rejected requests only causes one refresh.
+		'''
+		token = self.token
+		if token is not None and token != stale_token:
+			return token
+		return await self.regenerate()
+
+
+async def authorized_request(token, request):
+	'''
+	Call request with a bearer token. If token is a Token and twitter rejects
+	it as invalid or expired, the token is refreshed and the request is
+	retried, once. Other authentication errors, like a plain 401 for a
+	protected account, wouldn't be fixed by a new token, so they're raised.
+	'''
+	if not isinstance(token, Token):
+		return await request(token)
+
+	bearer_token = await token.get_token()
+	try:
+		return await request(bearer_token)
+	except InvalidTokenError:
+		bearer_token = await token.refresh(bearer_token)
+		return await request(bearer_token)
+
+
+class StaticToken(Token):
+	'''
+	A Token that always applies the same bearer token and never contacts the
+	token endpoint. Useful when testing against a mock twitter server.
+	'''
+	def __init__(self, bearer_token, session=None):
+		super().__init__(session, consumer_key=None, consumer_secret=None)
+		self.bearer_token = encode_bearer_token(bearer_token)
+		self.token = self.bearer_token
 
-class TwitterUser(namedtuple("TwitterUser", "id handle name")):
+	async def regenerate(self):
+		self.token = self.bearer_token
+		return self.token
+
+
+def get_verified_type(blob):
+	'''
+	Get the kind of verification a user has: None if they aren't verified, or
+	one of "blue", "business", or "government". Older API responses only
+	include a "verified" flag, which we treat as "blue".
+	'''
+	verified_type = blob.get("verified_type")
+	if verified_type in ("blue", "business", "government"):
+		return verified_type
+	elif blob.get("verified"):
+		return "blue"
+	else:
+		return None
+
+
+class TwitterUser(namedtuple("TwitterUser", "id handle name verified")):
 	__slots__ = ()
 
 	@lru_cache()
-	def __new__(cls, id, handle, name):
-		return super().__new__(cls, id, handle, name)
+	def __new__(cls, id, handle, name, verified=None):
+		return super().__new__(cls, id, handle, name, verified)
 
 	@classmethod
 	def from_user_json(cls, blob):
 		return cls(
 			blob["id_str"],
 			blob["screen_name"],
-			blob["name"]
+			blob["name"],
+			get_verified_type(blob),
 		)
 
+	@classmethod
+	def from_user_v2_json(cls, blob):
+		return cls(
+			blob["id"],
+			blob["username"],
+			blob["name"],
+			get_verified_type(blob),
+		)
 
-class Tweet(namedtuple("Tweet", "id user parent_id parent_user_id")):
+
+def get_tweet_text(blob):
+	'''
+	Get the displayable text of a tweet. This unescapes the HTML entities
+	twitter puts in tweet text, and strips the leading @mentions that twitter
+	adds to replies. The trailing t.co link that twitter adds for attached
+	media is stripped too, so links to the media themselves are appended
+	instead, one per line.
+	'''
+	# display_text_range counts code points of the unescaped text, so
+	# unescape before slicing
+	text = html_unescape(blob.get("full_text", blob.get("text", "")))
+	display_range = blob.get("display_text_range")
+
+	if display_range is not None:
+		start, end = display_range
+		text = text[start:end]
+
+	# extended_entities lists every attached photo, where entities only lists
+	# the first, but all of a tweet's photos share one expanded_url.
+	entities = blob.get("extended_entities") or blob.get("entities") or {}
+	media_urls = []
+	for media in entities.get("media", ()):
+		media_url = media.get("expanded_url", media.get("url"))
+		if media_url is not None and media_url not in media_urls:
+			media_urls.append(media_url)
+
+	return "\n".join([text, *media_urls])
+
+
+class Tweet(namedtuple(
+	"Tweet",
+	"id user parent_id parent_user_id parent_user_handle text conversation_id",
+)):
+	'''
+	conversation_id is the ID of the tweet at the root of the conversation.
+	It's only available from the v2 API, and is None otherwise.
+	'''
 	__slots__ = ()
 
 	@lru_cache()
-	def __new__(cls, id, user, parent, parent_user_id):
-		return super().__new__(cls, id, user, parent, parent_user_id)
+	def __new__(cls, id, user, parent, parent_user_id, parent_user_handle, text, conversation_id=None):
+		return super().__new__(
+			cls, id, user, parent, parent_user_id, parent_user_handle, text, conversation_id
+		)
 
 	@classmethod
 	def from_tweet_json(cls, blob):
@@ -131,56 +451,214 @@ class Tweet(namedtuple("Tweet", "id user parent_id parent_user_id")):
 			TwitterUser.from_user_json(blob["user"]),
 			blob["in_reply_to_status_id_str"],
 			blob["in_reply_to_user_id_str"],
+			blob.get("in_reply_to_screen_name"),
+			get_tweet_text(blob),
+		)
+
+	@classmethod
+	def from_tweet_v2_json(cls, blob, users):
+		'''
+		Create a tweet from a v2 tweet object. users is a dict of TwitterUsers
+		(by ID) from the response's includes, which should have the tweet's
+		author and the user it replies to. Twitter leaves suspended and
+		withheld authors out of the includes, in which case NoSuchUserError
+		is raised.
+		'''
+		author = users.get(blob.get("author_id"))
+		if author is None:
+			raise NoSuchUserError(None, "The tweet's author is unavailable")
+
+		parent = next(
+			(
+				reference["id"] for reference in blob.get("referenced_tweets", ())
+				if reference["type"] == "replied_to"
+			),
+			None,
 		)
+		parent_user_id = blob.get("in_reply_to_user_id") if parent is not None else None
+		parent_user = users.get(parent_user_id)
 
+		text = html_unescape(blob["text"])
+		if parent is not None:
+			text = LEADING_MENTIONS.sub("", text)
+
+		return cls(
+			blob["id"],
+			author,
+			parent,
+			parent_user_id,
+			parent_user.handle if parent_user is not None else None,
+			text,
+			blob.get("conversation_id"),
+		)
 
-# TODO: find a better way to report errors related to rate limiting
 
 @async_util.shared_concurrent
 async def get_tweet(*, session, token, tweet_id):
-	if isinstance(token, Token):
-		token = await token.get_token()
-
-	async with session.get(
-		url=TWEET_URL,
-		params={
-			"id": tweet_id,
-			"include_entities": "false",
-			"include_ext_alt_text": "false",
-		},
-		headers={
-			"Authorization": token,
-			"Accept": "application/json",
-		}
-	) as response:
-		response.raise_for_status()
-		result = await response.json()
-
+	params = {**TWEET_PARAMS, "id": tweet_id}
+
+	async def request(token):
+		async with session.get(
+			url=TWEET_URL,
+			params=params,
+			headers={
+				"Authorization": token,
+				"Accept": "application/json",
+			}
+		) as response:
+			return await read_response(response, not_found=NoSuchTweetError)
+
+	result = await authorized_request(token, request)
 	return Tweet.from_tweet_json(result)
 
 
+@async_util.shared_concurrent
+async def lookup_tweet(*, session, token, tweet_id):
+	'''
+	Get a tweet with statuses/lookup. This has its own rate limit, so it's
+	often still available when statuses/show (get_tweet) isn't. It silently
+	omits tweets that don't exist, rather than reporting an error, so a
+	deleted tweet can't be told apart from one that never existed, and is
+	reported as a plain NoSuchTweetError rather than a DeletedTweetError.
+	'''
+	params = {**TWEET_PARAMS, "id": tweet_id}
+
+	async def request(token):
+		async with session.get(
+			url=TWEET_LOOKUP_URL,
+			params=params,
+			headers={
+				"Authorization": token,
+				"Accept": "application/json",
+			}
+		) as response:
+			return await read_response(response)
+
+	results = await authorized_request(token, request)
+	if not results:
+		raise NoSuchTweetError(None, "No status found with that ID.")
+
+	return Tweet.from_tweet_json(results[0])
+
+
+@async_util.shared_concurrent
+async def get_tweet_v2(*, session, token, tweet_id):
+	'''
+	Get a tweet with the v2 API. Unlike get_tweet, this includes the tweet's
+	conversation_id.
+	'''
+	async def request(token):
+		async with session.get(
+			url=f"{TWEET_V2_URL}/{tweet_id}",
+			params=TWEET_V2_PARAMS,
+			headers={
+				"Authorization": token,
+				"Accept": "application/json",
+			}
+		) as response:
+			return await read_response(response, not_found=NoSuchTweetError)
+
+	result = await authorized_request(token, request)
+
+	# v2 reports missing tweets as an error alongside a successful status
+	if not isinstance(result, dict) or "data" not in result:
+		raise NoSuchTweetError(None, "No status found with that ID.")
+
+	return Tweet.from_tweet_v2_json(result["data"], get_included_users(result))
+
+
+def get_included_users(result):
+	return {
+		user["id"]: TwitterUser.from_user_v2_json(user)
+		for user in result.get("includes", {}).get("users", ())
+	}
+
+
+@async_util.shared_concurrent
+async def get_conversation_page(*, session, token, conversation_id, next_token=None):
+	'''
+	Get one page of the tweets in a conversation with the v2 recent search
+	API. Returns a dict of Tweets by ID, and the next_token for the next page,
+	or None if this is the last page.
+	'''
+	params = {
+		**TWEET_V2_PARAMS,
+		"query": f"conversation_id:{conversation_id}",
+		"max_results": "100",
+	}
+	if next_token is not None:
+		params["next_token"] = next_token
+
+	async def request(token):
+		async with session.get(
+			url=SEARCH_RECENT_URL,
+			params=params,
+			headers={
+				"Authorization": token,
+				"Accept": "application/json",
+			}
+		) as response:
+			return await read_response(response)
+
+	result = await authorized_request(token, request)
+
+	tweets = {}
+	users = get_included_users(result)
+	for blob in result.get("data", ()):
+		# Tweets by unavailable authors are left for the walk to report
+		try:
+			tweet = Tweet.from_tweet_v2_json(blob, users)
+		except NoSuchUserError:
+			continue
+		tweets[tweet.id] = tweet
+
+	return tweets, result.get("meta", {}).get("next_token")
+
+
+async def get_conversation(*, session, token, conversation_id, max_pages=MAX_CONVERSATION_PAGES):
+	'''
+	Get the tweets in a conversation with the v2 recent search API. This is
+	an async generator, which yields a dict of Tweets by ID for each page of
+	results, each of which is a separate request. At most max_pages pages are
+	fetched, so very large conversations may be incomplete. Recent search only
+	covers about the last 7 days, so older conversations come back empty.
+	'''
+	next_token = None
+
+	for _ in range(max_pages):
+		tweets, next_token = await get_conversation_page(
+			session=session,
+			token=token,
+			conversation_id=conversation_id,
+			next_token=next_token,
+		)
+		yield tweets
+
+		if next_token is None:
+			break
+
+
 @async_util.shared_concurrent
 async def get_user_tweets(*, session, token, user_id, max_tweet, count=200):
-	if isinstance(token, Token):
-		token = await token.get_token()
-
-	async with session.get(
-		url=USER_TIMELINE_URL,
-		params={
-			"user_id": user_id,
-			"count": count,
-			"max_id": max_tweet,
-			"exclude_replies": "false",
-			"include_rts": "true",
-		},
-		headers={
-			"Authorization": token,
-			"Accept": "application/json"
-		},
-	) as response:
-		# TODO: handle errors better
-		response.raise_for_status()
-		result = await response.json()
+	async def request(token):
+		async with session.get(
+			url=USER_TIMELINE_URL,
+			params={
+				"user_id": user_id,
+				"count": count,
+				"max_id": max_tweet,
+				"exclude_replies": "false",
+				"include_rts": "true",
+				"tweet_mode": "extended",
+			},
+			headers={
+				"Authorization": token,
+				"Accept": "application/json"
+			},
+		) as response:
+			return await read_response(response, not_found=NoSuchUserError)
+
+	result = await authorized_request(token, request)
 
 	# Ordinarily I dislike pre-emptively unrolling iterators like this, but in
 	# this case we don't want to carry around the immense json value.
@@ -1,4 +1,5 @@
 import functools
+import hashlib
 import re
 import inspect
 import enum
@@ -6,6 +7,8 @@ from json import dumps
 
 from aiohttp import web
 
+from bobbin import error_reporting
+
 
 def dump_json(**kwargs):
 	return dumps(kwargs, check_circular=False, separators=(',', ':'))
@@ -18,6 +21,61 @@ def bad_request_json(error, **kwargs):
 	)
 
 
+def not_found_json(error, **kwargs):
+	return web.HTTPNotFound(
+		text=dump_json(error=error, **kwargs),
+		content_type='application/json'
+	)
+
+
+def gone_json(error, **kwargs):
+	return web.HTTPGone(
+		text=dump_json(error=error, **kwargs),
+		content_type='application/json'
+	)
+
+
+def service_unavailable_json(error, *, retry_after, **kwargs):
+	return web.HTTPServiceUnavailable(
+		headers={'Retry-After': str(retry_after)},
+		text=dump_json(error=error, **kwargs),
+		content_type='application/json'
+	)
+
+
+def make_etag(text):
+	'''
+	A strong ETag for a response body. It only depends on the body, so it's
+	the same across processes and restarts.
+	'''
+	return '"{}"'.format(hashlib.sha256(text.encode('utf8')).hexdigest()[:32])
+
+
+def etag_matches(request, etag):
+	if_none_match = request.headers.get('If-None-Match')
+	if if_none_match is None:
+		return False
+
+	return any(
+		candidate == '*' or candidate == etag or candidate == f"W/{etag}"
+		for candidate in map(str.strip, if_none_match.split(','))
+	)
+
+
+def conditional_response(request, *, text, content_type, headers=None):
+	'''
+	Create a response with an ETag, or raise a 304 Not Modified if the client
+	already has this exact body.
+	'''
+	headers = dict(headers or ())
+	etag = headers['ETag'] = make_etag(text)
+
+	if etag_matches(request, etag):
+		raise web.HTTPNotModified(headers=headers)
+
+	return web.Response(text=text, content_type=content_type, headers=headers)
+
+
 def with_context(handler=None, **context):
 	if handler is None:
 		return lambda handler: with_context(handler, **context)
@@ -327,6 +385,31 @@ def routes(*routes):
 	return compose_handlers(map(_make_route, routes), RouteNotFound)
 
 
+def report_errors(sink, handler=None):
+	'''
+	Report any exception raised by the handler to sink(error, context) before
+	re-raising it. HTTP exceptions are responses rather than errors, so they
+	aren't reported.
+	'''
+	if handler is None:
+		return lambda handler: report_errors(sink, handler)
+
+	@functools.wraps(handler)
+	async def report_errors_wrapper(request, **kwargs):
+		try:
+			return await handler(request, **kwargs)
+		except web.HTTPException:
+			raise
+		except Exception as e:
+			error_reporting.safe_report(sink, e, {
+				"method": request.method,
+				"path": request.path,
+			})
+			raise
+
+	return report_errors_wrapper
+
+
 def shitty_logging(handler):
 	def shitty_log_handler(request, **context):
 		print(request)
@@ -0,0 +1,242 @@
+import asyncio
+import json
+
+import aiohttp
+
+from bobbin import twitter
+from bobbin.async_cache import KeyNotFound
+
+
+def run(coro):
+	return asyncio.get_event_loop().run_until_complete(coro)
+
+
+class FakeResponse:
+	'''
+	Just enough of an aiohttp ClientResponse for the twitter module. body is
+	JSON-encoded unless it's already bytes.
+	'''
+	def __init__(self, body=None, *, status=200, headers=None):
+		self.status = status
+		self.headers = headers or {}
+		self.body = body if isinstance(body, bytes) else json.dumps(body).encode()
+		self.content = FakeStream(self.body)
+
+	async def json(self, content_type="application/json"):
+		return json.loads(self.body)
+
+	def raise_for_status(self):
+		if self.status >= 400:
+			raise aiohttp.ClientResponseError(None, (), status=self.status)
+
+	async def __aenter__(self):
+		return self
+
+	async def __aexit__(self, *exc_info):
+		pass
+
+
+class FakeStream:
+	'''
+	A response body that comes in chunks of at most chunk_size bytes, like
+	a chunked response would
+	'''
+	def __init__(self, body, chunk_size=1024):
+		self.body = body
+		self.chunk_size = chunk_size
+
+	async def read(self, n=-1):
+		n = self.chunk_size if n < 0 else min(n, self.chunk_size)
+		chunk, self.body = self.body[:n], self.body[n:]
+		return chunk
+
+
+class FakeSession:
+	'''
+	An aiohttp ClientSession stand-in. respond is called with (method, url,
+	params) for each request, and returns a FakeResponse. Every request is
+	recorded in requests, and its headers in headers.
+	'''
+	def __init__(self, respond):
+		self.respond = respond
+		self.requests = []
+		self.headers = []
+
+	def request(self, method, url, params=None, headers=None):
+		self.requests.append((method, url, params))
+		self.headers.append(headers or {})
+		return self.respond(method, url, params or {})
+
+	def get(self, url, *, params=None, headers=None, **kwargs):
+		return self.request("GET", url, params, headers)
+
+	def post(self, url, *, headers=None, **kwargs):
+		return self.request("POST", url, None, headers)
+
+
+class DictCache:
+	def __init__(self):
+		self.data = {}
+
+	async def get(self, key):
+		try:
+			return self.data[key]
+		except KeyError:
+			raise KeyNotFound(key)
+
+	async def write(self, key, value):
+		self.data[key] = value
+
+
+def user_json(user_id):
+	return {
+		"id_str": str(user_id),
+		"screen_name": f"user{user_id}",
+		"name": f"User {user_id}",
+	}
+
+
+def tweet_json(tweet_id, user_id, parent_id=None, parent_user_id=None, text=None):
+	return {
+		"id_str": str(tweet_id),
+		"user": user_json(user_id),
+		"in_reply_to_status_id_str": None if parent_id is None else str(parent_id),
+		"in_reply_to_user_id_str": None if parent_user_id is None else str(parent_user_id),
+		"in_reply_to_screen_name": None if parent_user_id is None else f"user{parent_user_id}",
+		"full_text": text if text is not None else f"tweet {tweet_id}",
+	}
+
+
+def make_chain(authors, start=1):
+	'''
+	Make a chain of replies as tweet JSON blobs, where authors[i] is the user
+	ID of the i'th tweet. Tweet IDs count up from start.
+	'''
+	tweets = []
+	for offset, user_id in enumerate(authors):
+		parent = tweets[-1] if tweets else None
+		tweets.append(tweet_json(
+			start + offset,
+			user_id,
+			parent_id=None if parent is None else parent["id_str"],
+			parent_user_id=None if parent is None else parent["user"]["id_str"],
+		))
+	return tweets
+
+
+class FakeTwitter:
+	'''
+	Serves the twitter endpoints bobbin uses from a set of tweet JSON blobs,
+	for use with FakeSession. Missing tweets are reported as deleted. User
+	timelines come back empty if timelines is false, as if the tweets were
+	too far back to reach. Likewise, recent search returns nothing if search
+	is false, as if the conversation were too old; otherwise, it returns a
+	conversation's tweets in pages of page_size. If show_rate_limited is
+	true, statuses/show responds as if bobbin were rate limited, but
+	statuses/lookup still works.
+	'''
+	def __init__(self, tweets, *, timelines=True, search=True, page_size=100, show_rate_limited=False):
+		self.tweets = {tweet["id_str"]: tweet for tweet in tweets}
+		self.timelines = timelines
+		self.search = search
+		self.page_size = page_size
+		self.show_rate_limited = show_rate_limited
+		self.session = FakeSession(self.respond)
+
+	@property
+	def requests(self):
+		return self.session.requests
+
+	def endpoint_requests(self, url):
+		return [params for method, request_url, params in self.requests if request_url == url]
+
+	def respond(self, method, url, params):
+		if url == twitter.TWEET_URL:
+			if self.show_rate_limited:
+				return FakeResponse(
+					{"errors": [{"code": 88, "message": "Rate limit exceeded"}]},
+					status=429,
+				)
+
+			tweet = self.tweets.get(params["id"])
+			if tweet is None:
+				return deleted_response()
+			return FakeResponse(tweet)
+
+		elif url == twitter.TWEET_LOOKUP_URL:
+			return FakeResponse([
+				self.tweets[tweet_id] for tweet_id in params["id"].split(",")
+				if tweet_id in self.tweets
+			])
+
+		elif url == twitter.USER_TIMELINE_URL:
+			timeline = [] if not self.timelines else sorted(
+				(
+					tweet for tweet in self.tweets.values()
+					if tweet["user"]["id_str"] == params["user_id"]
+					and int(tweet["id_str"]) <= int(params["max_id"])
+				),
+				key=lambda tweet: int(tweet["id_str"]),
+				reverse=True,
+			)
+			return FakeResponse(timeline[:int(params["count"])])
+
+		elif url == twitter.SEARCH_RECENT_URL:
+			conversation_id = params["query"].split(":")[1]
+			conversation = [] if not self.search else sorted(
+				(
+					tweet for tweet in self.tweets.values()
+					if self.conversation_id(tweet) == conversation_id
+				),
+				key=lambda tweet: int(tweet["id_str"]),
+			)
+
+			start = int(params.get("next_token", 0))
+			end = start + self.page_size
+			response = self.v2_response(conversation[start:end])
+			if end < len(conversation):
+				response["meta"] = {"next_token": str(end)}
+			return FakeResponse(response)
+
+		# Recent search's URL also starts with this
+		elif url.startswith(twitter.TWEET_V2_URL + "/"):
+			tweet = self.tweets.get(url.rsplit("/", 1)[1])
+			if tweet is None:
+				return FakeResponse({"errors": [{"title": "Not Found Error"}]})
+			response = self.v2_response([tweet])
+			response["data"] = response["data"][0]
+			return FakeResponse(response)
+
+		raise AssertionError(f"Unexpected request to {url}")
+
+	def conversation_id(self, tweet):
+		while tweet["in_reply_to_status_id_str"] in self.tweets:
+			tweet = self.tweets[tweet["in_reply_to_status_id_str"]]
+		return tweet["in_reply_to_status_id_str"] or tweet["id_str"]
+
+	def v2_response(self, tweets):
+		data = []
+		users = {}
+		for tweet in tweets:
+			blob = {
+				"id": tweet["id_str"],
+				"author_id": tweet["user"]["id_str"],
+				"conversation_id": self.conversation_id(tweet),
+				"text": tweet["full_text"],
+			}
+			if tweet["in_reply_to_status_id_str"] is not None:
+				blob["referenced_tweets"] = [{"type": "replied_to", "id": tweet["in_reply_to_status_id_str"]}]
+				blob["in_reply_to_user_id"] = tweet["in_reply_to_user_id_str"]
+			data.append(blob)
+
+			user = tweet["user"]
+			users[user["id_str"]] = {"id": user["id_str"], "username": user["screen_name"], "name": user["name"]}
+
+		return {"data": data, "includes": {"users": list(users.values())}}
+
+
+def deleted_response():
+	return FakeResponse(
+		{"errors": [{"code": 144, "message": "No status found with that ID."}]},
+		status=404,
+	)
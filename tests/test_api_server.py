@@ -0,0 +1,376 @@
+import asyncio
+import json
+import unittest
+from collections import Counter
+from types import SimpleNamespace
+
+import aiohttp
+from aiohttp import web
+
+from bobbin import api_server, tweetbox, twitter
+from bobbin.twitter import Tweet
+
+from support import DictCache, FakeTwitter, make_chain, run, tweet_json
+
+
+def make_request(headers=None, **query):
+	return SimpleNamespace(method="GET", query=query, headers=headers or {})
+
+
+def make_thread(tail):
+	tweet = Tweet.from_tweet_json(tweet_json(tail, 1))
+	authors = tweetbox.AuthorCounter()
+	authors.add(tweet)
+	return tweetbox.Thread([tweet], False, Counter(), {tweet.id: "api"}, None, None, False, authors)
+
+
+class ThreadHandlerTest(unittest.TestCase):
+	def handle(
+		self, request, *, builds, build_budget=None, thread_etags=None,
+		prewarm=None, debug_headers=False, collapse_after=None,
+	):
+		'''
+		Call the thread handler, and get its response, whether it was returned
+		or raised
+		'''
+		try:
+			return run(api_server.thread_handler(
+				request,
+				builds=builds,
+				build_budget=build_budget,
+				thread_etags=DictCache() if thread_etags is None else thread_etags,
+				prewarm=prewarm,
+				debug_headers=debug_headers,
+				collapse_after=collapse_after,
+			))
+		except web.HTTPException as e:
+			return e
+
+
+class FakeTwitterTest(ThreadHandlerTest):
+	'''
+	Thread handler tests with threads really built, from tweets served by a
+	FakeTwitter
+	'''
+	def serve(self, tweets):
+		self.fake_twitter = FakeTwitter(tweets)
+		self.cache = DictCache()
+
+		async def get_thread(**kwargs):
+			return await tweetbox.get_thread(
+				session=self.fake_twitter.session,
+				cache=self.cache,
+				token="Bearer token",
+				**kwargs,
+			)
+
+		self.builds = tweetbox.ThreadBuilds(get_thread, max_builds=1)
+
+	def request(self, headers=None, *, collapse_after=None, debug_headers=False, **query):
+		return self.handle(
+			make_request(headers, **query),
+			builds=self.builds,
+			collapse_after=collapse_after,
+			debug_headers=debug_headers,
+		)
+
+	def content(self, **kwargs):
+		response = self.request(**kwargs)
+		self.assertEqual(response.status, 200)
+		return json.loads(response.text)
+
+
+class TestCollapse(FakeTwitterTest):
+	def test_initially_shown(self):
+		self.serve(make_chain([1] * 5))
+
+		self.assertEqual(self.content(tail="5")["initially_shown"], 5)
+		self.assertEqual(self.content(tail="5", collapse_after=3)["initially_shown"], 3)
+		self.assertEqual(self.content(tail="2", collapse_after=3)["initially_shown"], 2)
+
+
+class TestHeadAfterTail(FakeTwitterTest):
+	def test_head_after_tail_is_rejected(self):
+		self.serve(make_chain([1] * 3))
+		response = self.request(tail="2", head="3")
+
+		self.assertEqual(response.status, 400)
+		self.assertEqual(json.loads(response.text)["param"], "head")
+		self.assertEqual(self.fake_twitter.requests, [])
+
+
+class TestSummary(FakeTwitterTest):
+	def test_tweet_count_and_reading_time(self):
+		words = " ".join(["word"] * 100)
+		self.serve([
+			tweet_json(1, 1, text=words),
+			tweet_json(2, 1, parent_id=1, parent_user_id=1, text=words),
+			tweet_json(3, 1, parent_id=2, parent_user_id=1, text=words),
+		])
+
+		content = self.content(tail="3")
+		self.assertEqual(content["tweet_count"], 3)
+		self.assertEqual(content["reading_minutes"], 2)
+
+		# Short threads still take a minute
+		content = self.content(tail="1")
+		self.assertEqual(content["tweet_count"], 1)
+		self.assertEqual(content["reading_minutes"], 1)
+
+
+class TestSharePath(FakeTwitterTest):
+	def test_share_path(self):
+		self.serve(make_chain([1] * 3))
+
+		self.assertEqual(self.content(tail="3")["share_path"], "/thread/3")
+		self.assertEqual(self.content(tail="3", head="2")["share_path"], "/thread/3/2")
+
+	def test_options_are_kept(self):
+		self.serve(make_chain([2, 1, 1]))
+
+		self.assertEqual(self.content(tail="3", context="1")["share_path"], "/thread/3?context=1")
+		self.assertEqual(self.content(tail="3", author_only="1")["share_path"], "/thread/3?author_only=1")
+		self.assertEqual(
+			self.content(tail="3", head="2", context="1", author_only="1")["share_path"],
+			"/thread/3/2?context=1&author_only=1",
+		)
+		self.assertEqual(self.content(tail="3", author_only="0")["share_path"], "/thread/3")
+
+
+class TestReplyingTo(FakeTwitterTest):
+	def test_replying_to(self):
+		self.serve(make_chain([2, 1, 1]))
+
+		self.assertIsNone(self.content(tail="3")["replying_to"])
+		self.assertEqual(self.content(tail="3", author_only="1")["replying_to"], "user2")
+		self.assertEqual(self.content(tail="3", context="0")["replying_to"], "user1")
+
+
+class TestAuthorship(FakeTwitterTest):
+	def test_thread_with_an_author(self):
+		self.serve(make_chain([1, 2, 1]))
+		content = self.content(tail="3")
+
+		self.assertEqual(content["author"]["handle"], "user1")
+		self.assertEqual(content["authorship"]["leader"]["handle"], "user1")
+		self.assertAlmostEqual(content["authorship"]["share"], 2 / 3)
+		self.assertEqual(content["authorship"]["participants"], 2)
+
+	def test_tied_conversation(self):
+		self.serve(make_chain([1, 2]))
+		content = self.content(tail="2")
+
+		self.assertIsNone(content["author"])
+		self.assertEqual(content["authorship"], {"leader": None, "share": 0.5, "participants": 2})
+
+
+class TestDebugInfo(FakeTwitterTest):
+	def test_cache_header(self):
+		self.serve(make_chain([1] * 3))
+
+		response = self.request(tail="3", debug_headers=True)
+		self.assertEqual(response.headers["X-Bobbin-Cache"], "hits=0;misses=3;api=2")
+
+		response = self.request(tail="3", debug_headers=True)
+		self.assertEqual(response.headers["X-Bobbin-Cache"], "hits=3;misses=0;api=0")
+
+		self.assertNotIn("X-Bobbin-Cache", self.request(tail="3").headers)
+
+	def test_sources(self):
+		self.serve(make_chain([1] * 3))
+
+		self.assertEqual(self.content(tail="3", debug="1")["sources"], ["timeline", "timeline", "api"])
+		self.assertEqual(self.content(tail="3", debug="1")["sources"], ["cache"] * 3)
+		self.assertNotIn("sources", self.content(tail="3"))
+
+
+class TestTextFormats(FakeTwitterTest):
+	def setUp(self):
+		self.serve([
+			tweet_json(1, 1, text="Fish &amp; *chips*"),
+			tweet_json(2, 1, parent_id=1, parent_user_id=1, text="More"),
+		])
+
+	def test_text(self):
+		response = self.request(tail="2", format="text")
+
+		self.assertEqual(response.content_type, "text/plain")
+		self.assertEqual(response.text, (
+			"Thread by @user1\n\n"
+			"User 1 (@user1):\nFish & *chips*\n\n"
+			"User 1 (@user1):\nMore\n"
+		))
+
+	def test_markdown(self):
+		response = self.request(tail="1", format="md")
+
+		self.assertEqual(response.content_type, "text/markdown")
+		self.assertEqual(response.text, (
+			"# Tweet by @user1\n\n"
+			"**User 1** ([@user1](https://twitter.com/user1/status/1))\n\n"
+			"Fish & \\*chips\\*\n"
+		))
+
+
+class TestBuildBudget(ThreadHandlerTest):
+	def test_slow_builds(self):
+		release = asyncio.Event()
+
+		async def get_thread(*, tail, head, context, author_only):
+			await release.wait()
+			return make_thread(tail)
+
+		builds = tweetbox.ThreadBuilds(get_thread, max_builds=1)
+
+		response = self.handle(make_request(tail="1"), builds=builds, build_budget=0.01)
+		self.assertEqual(response.status, 202)
+		self.assertEqual(response.headers["Retry-After"], str(api_server.BUILDING_RETRY_AFTER))
+
+		# The abandoned build still holds the only slot
+		response = self.handle(make_request(tail="2"), builds=builds, build_budget=0.01)
+		self.assertEqual(response.status, 503)
+
+		# Asking again joins the running build
+		release.set()
+		response = self.handle(make_request(tail="1"), builds=builds, build_budget=0.01)
+		self.assertEqual(response.status, 200)
+		self.assertEqual(json.loads(response.text)["thread"], ["1"])
+		self.assertEqual(builds.running, {})
+
+
+class TestBuildErrors(ThreadHandlerTest):
+	def handle_error(self, error):
+		async def get_thread(**kwargs):
+			raise error
+
+		builds = tweetbox.ThreadBuilds(get_thread, max_builds=1)
+		return self.handle(make_request(tail="1"), builds=builds)
+
+	def test_statuses(self):
+		cases = [
+			(twitter.DeletedTweetError(144, "No status found with that ID."), 410),
+			(twitter.NoSuchTweetError(34, "Sorry, that page does not exist."), 404),
+			(twitter.SuspendedUserError(63, "User has been suspended."), 404),
+			(twitter.ProtectedTweetError(179, "Sorry, you are not authorized to see this status."), 404),
+			(tweetbox.MissingAncestorError("1"), 404),
+			(tweetbox.InvalidThreadError("1"), 404),
+			(twitter.RateLimitError(88, "Rate limit exceeded", retry_after=0.2), 503),
+			(twitter.TokenError("Couldn't get a bearer token from twitter"), 503),
+			(twitter.AuthenticationError(89, "Invalid or expired token."), 503),
+			(aiohttp.ClientResponseError(None, (), status=500), 503),
+			(aiohttp.ClientError(), 503),
+			(asyncio.TimeoutError(), 503),
+		]
+		for error, status in cases:
+			with self.subTest(error=error):
+				self.assertEqual(self.handle_error(error).status, status)
+
+	def test_rate_limit_retry_after(self):
+		response = self.handle_error(twitter.RateLimitError(88, "Rate limit exceeded", retry_after=0.2))
+		self.assertEqual(response.headers["Retry-After"], "1")
+
+		response = self.handle_error(twitter.RateLimitError(429, "Too many requests"))
+		self.assertEqual(response.headers["Retry-After"], str(api_server.BUSY_RETRY_AFTER))
+
+	def test_unexpected_errors_propagate(self):
+		with self.assertRaises(KeyError):
+			self.handle_error(KeyError("oops"))
+
+		# Clients shouldn't retry requests that twitter rejected
+		with self.assertRaises(aiohttp.ClientResponseError):
+			self.handle_error(aiohttp.ClientResponseError(None, (), status=403))
+
+
+class TestETags(ThreadHandlerTest):
+	def setUp(self):
+		self.calls = []
+
+		async def get_thread(*, tail, head, context, author_only):
+			self.calls.append(tail)
+			return make_thread(tail)
+
+		self.builds = tweetbox.ThreadBuilds(get_thread, max_builds=1)
+		self.thread_etags = DictCache()
+
+	def request(self, headers=None, **query):
+		return self.handle(
+			make_request(headers, **query),
+			builds=self.builds,
+			thread_etags=self.thread_etags,
+		)
+
+	def test_revalidate_without_building(self):
+		etag = self.request(tail="1").headers["ETag"]
+
+		response = self.request({"If-None-Match": etag}, tail="1")
+		self.assertEqual(response.status, 304)
+		self.assertEqual(response.headers["ETag"], etag)
+		self.assertEqual(self.calls, ["1"])
+
+	def test_formats_have_their_own_etags(self):
+		etag = self.request(tail="1").headers["ETag"]
+
+		response = self.request({"If-None-Match": etag}, tail="1", format="text")
+		self.assertEqual(response.status, 200)
+		self.assertNotEqual(response.headers["ETag"], etag)
+
+	def test_unknown_etags_are_checked_after_building(self):
+		etag = self.request(tail="1").headers["ETag"]
+		self.thread_etags.data.clear()
+
+		response = self.request({"If-None-Match": etag}, tail="1")
+		self.assertEqual(response.status, 304)
+		self.assertEqual(self.calls, ["1", "1"])
+
+	def test_debug_responses_are_always_built(self):
+		etag = self.request(tail="1", debug="1").headers["ETag"]
+
+		response = self.request({"If-None-Match": etag}, tail="1", debug="1")
+		self.assertEqual(response.status, 304)
+		self.assertEqual(self.calls, ["1", "1"])
+
+
+class TestPrewarm(ThreadHandlerTest):
+	def test_requested_threads_are_queued(self):
+		async def get_thread(*, tail, head, context, author_only):
+			return make_thread(tail)
+
+		builds = tweetbox.ThreadBuilds(get_thread, max_builds=1)
+		prewarm = tweetbox.PrewarmQueue(builds, max_size=2, interval=60)
+
+		self.handle(make_request(tail="1"), builds=builds, prewarm=prewarm)
+		self.handle(make_request(tail="2", context="3"), builds=builds, prewarm=prewarm)
+
+		self.assertEqual(list(prewarm.threads.values()), [
+			dict(tail="1", head=None, context=None, author_only=False),
+			dict(tail="2", head=None, context=3, author_only=False),
+		])
+
+	def test_revalidated_threads_stay_queued(self):
+		async def get_thread(*, tail, head, context, author_only):
+			return make_thread(tail)
+
+		builds = tweetbox.ThreadBuilds(get_thread, max_builds=1)
+		prewarm = tweetbox.PrewarmQueue(builds, max_size=2, interval=60)
+		thread_etags = DictCache()
+
+		def request(tail, headers=None):
+			return self.handle(
+				make_request(headers, tail=tail),
+				builds=builds,
+				prewarm=prewarm,
+				thread_etags=thread_etags,
+			)
+
+		etag = request("1").headers["ETag"]
+		request("2")
+		request("3")
+
+		# "1" fell out of the queue, but being revalidated puts it back
+		response = request("1", {"If-None-Match": etag})
+		self.assertEqual(response.status, 304)
+		self.assertEqual([thread["tail"] for thread in prewarm.threads.values()], ["3", "1"])
+
+
+if __name__ == "__main__":
+	unittest.main()
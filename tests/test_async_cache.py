@@ -0,0 +1,60 @@
+import unittest
+
+from bobbin.async_cache import AsyncLRUCache, KeyNotFound, get_size_of
+
+from support import run
+
+VALUE = b"x" * 100
+
+
+def contents(cache, keys):
+	found = []
+	for key in keys:
+		try:
+			run(cache.get(key))
+		except KeyNotFound:
+			pass
+		else:
+			found.append(key)
+	return found
+
+
+class TestAsyncLRUCache(unittest.TestCase):
+	def test_evicts_least_recently_used(self):
+		cache = AsyncLRUCache(max_size=get_size_of(VALUE) * 3)
+		for key in "abc":
+			run(cache.write(key, VALUE))
+
+		# Reading "a" makes "b" the least recently used
+		self.assertEqual(run(cache.get("a")), VALUE)
+		run(cache.write("d", VALUE))
+
+		self.assertEqual(len(cache), 3)
+		self.assertEqual(contents(cache, "abcd"), ["a", "c", "d"])
+
+	def test_missing_keys(self):
+		cache = AsyncLRUCache(max_size=1024)
+		with self.assertRaises(KeyNotFound):
+			run(cache.get("a"))
+
+
+class TestExpiry(unittest.TestCase):
+	def test_entries_expire(self):
+		now = 0
+		cache = AsyncLRUCache(max_size=1024 * 1024, ttl=60, timer=lambda: now)
+		run(cache.write("a", VALUE))
+
+		now = 30
+		run(cache.write("b", VALUE))
+		self.assertEqual(contents(cache, "ab"), ["a", "b"])
+
+		# Reading an entry doesn't extend its life
+		now = 60
+		self.assertEqual(contents(cache, "ab"), ["b"])
+
+		now = 90
+		self.assertEqual(contents(cache, "ab"), [])
+
+
+if __name__ == "__main__":
+	unittest.main()
@@ -0,0 +1,126 @@
+import asyncio
+import unittest
+
+from bobbin.async_util import KeyedSemaphore, shared_concurrent
+
+from support import run
+
+
+async def settle():
+	# Give every runnable task a chance to run
+	for _ in range(5):
+		await asyncio.sleep(0)
+
+
+class TestKeyedSemaphore(unittest.TestCase):
+	def test_limit_per_key(self):
+		async def test():
+			semaphore = KeyedSemaphore(2)
+			release = asyncio.Event()
+			holding = []
+
+			async def hold(key, name):
+				async with semaphore.acquire(key):
+					holding.append(name)
+					await release.wait()
+
+			tasks = [
+				asyncio.ensure_future(hold("a", "a1")),
+				asyncio.ensure_future(hold("a", "a2")),
+				asyncio.ensure_future(hold("a", "a3")),
+				asyncio.ensure_future(hold("b", "b1")),
+			]
+			await settle()
+
+			# a3 waits for one of the first two, but b is a separate limit
+			self.assertEqual(holding, ["a1", "a2", "b1"])
+
+			release.set()
+			await asyncio.gather(*tasks)
+			self.assertEqual(holding, ["a1", "a2", "b1", "a3"])
+
+		run(test())
+
+	def test_discards_unused_keys(self):
+		async def test():
+			semaphore = KeyedSemaphore(1)
+
+			async with semaphore.acquire("a"):
+				async with semaphore.acquire("b"):
+					self.assertEqual(set(semaphore.semaphores), {"a", "b"})
+				self.assertEqual(set(semaphore.semaphores), {"a"})
+
+			self.assertEqual(semaphore.semaphores, {})
+
+		run(test())
+
+	def test_discards_key_after_error(self):
+		async def test():
+			semaphore = KeyedSemaphore(1)
+
+			with self.assertRaises(ValueError):
+				async with semaphore.acquire("a"):
+					raise ValueError()
+
+			self.assertEqual(semaphore.semaphores, {})
+
+		run(test())
+
+	def test_cancelled_waiter(self):
+		async def test():
+			semaphore = KeyedSemaphore(1)
+			release = asyncio.Event()
+
+			async def hold():
+				async with semaphore.acquire("a"):
+					await release.wait()
+
+			async def wait():
+				async with semaphore.acquire("a"):
+					self.fail("The waiter shouldn't have acquired the semaphore")
+
+			holder = asyncio.ensure_future(hold())
+			waiter = asyncio.ensure_future(wait())
+			await settle()
+			self.assertEqual(semaphore.semaphores["a"][1], 2)
+
+			waiter.cancel()
+			await settle()
+			self.assertTrue(waiter.cancelled())
+			self.assertEqual(semaphore.semaphores["a"][1], 1)
+
+			release.set()
+			await holder
+			self.assertEqual(semaphore.semaphores, {})
+
+		run(test())
+
+
+class TestSharedConcurrent(unittest.TestCase):
+	def test_concurrent_calls_are_shared(self):
+		async def test():
+			calls = []
+
+			@shared_concurrent
+			async def lookup(*, key):
+				calls.append(key)
+				await asyncio.sleep(0)
+				return object()
+
+			first, second, other = await asyncio.gather(
+				lookup(key=1),
+				lookup(key=1),
+				lookup(key=2),
+			)
+			self.assertIs(first, second)
+			self.assertIsNot(first, other)
+
+			# Results aren't kept once the call is finished
+			self.assertIsNot(await lookup(key=1), first)
+			self.assertEqual(calls, [1, 2, 1])
+
+		run(test())
+
+
+if __name__ == "__main__":
+	unittest.main()
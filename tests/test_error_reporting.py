@@ -0,0 +1,70 @@
+import asyncio
+import contextlib
+import io
+import unittest
+
+from bobbin import error_reporting
+
+from support import run
+
+
+class TestLoadSink(unittest.TestCase):
+	def test_load_sink(self):
+		sink = error_reporting.load_sink("bobbin.error_reporting:print_error")
+		self.assertIs(sink, error_reporting.print_error)
+
+	def test_invalid_specs(self):
+		with self.assertRaises(ValueError):
+			error_reporting.load_sink("bobbin.error_reporting.print_error")
+
+		with self.assertRaises(AttributeError):
+			error_reporting.load_sink("bobbin.error_reporting:no_such_sink")
+
+		with self.assertRaises(ImportError):
+			error_reporting.load_sink("bobbin.no_such_module:print_error")
+
+
+class TestSafeReport(unittest.TestCase):
+	def test_broken_sinks_dont_raise(self):
+		def broken_sink(error, context):
+			raise RuntimeError("broken")
+
+		stderr = io.StringIO()
+		with contextlib.redirect_stderr(stderr):
+			error_reporting.safe_report(broken_sink, KeyError("oops"), {})
+		self.assertIn("RuntimeError: broken", stderr.getvalue())
+
+
+class TestReportTaskErrors(unittest.TestCase):
+	def finish(self, coro):
+		reports = []
+
+		async def test():
+			task = error_reporting.report_task_errors(
+				asyncio.ensure_future(coro),
+				lambda error, context: reports.append((error, context)),
+				{"task": "token warmup"},
+			)
+			await asyncio.wait([task])
+			await asyncio.sleep(0)
+
+		run(test())
+		return reports
+
+	def test_failures_are_reported(self):
+		error = RuntimeError("twitter is down")
+
+		async def fail():
+			raise error
+
+		self.assertEqual(self.finish(fail()), [(error, {"task": "token warmup"})])
+
+	def test_successes_are_not_reported(self):
+		async def succeed():
+			return "Bearer token"
+
+		self.assertEqual(self.finish(succeed()), [])
+
+
+if __name__ == "__main__":
+	unittest.main()
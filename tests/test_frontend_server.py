@@ -0,0 +1,109 @@
+import base64
+import hashlib
+import pathlib
+import tempfile
+import unittest
+from types import SimpleNamespace
+
+from bobbin import frontend_server
+
+from support import run
+
+INDEX_HTML = '''<html>
+<head>
+<script src="/static/dist/bundle.js"></script>
+<script>window.dataLayer = [];</script>
+</head>
+</html>
+'''
+
+
+def script_hash(script):
+	digest = hashlib.sha256(script.encode('utf8')).digest()
+	return f"'sha256-{base64.b64encode(digest).decode('ascii')}'"
+
+
+def parse_policy(policy):
+	directives = {}
+	for directive in policy.split("; "):
+		name, *values = directive.split(" ")
+		directives[name] = values
+	return directives
+
+
+class TestContentSecurityPolicy(unittest.TestCase):
+	def test_inline_scripts_are_allowed_by_hash(self):
+		policy = parse_policy(frontend_server.make_content_security_policy(INDEX_HTML))
+
+		self.assertIn(script_hash("window.dataLayer = [];"), policy["script-src"])
+		self.assertIn("'self'", policy["script-src"])
+		self.assertNotIn("'unsafe-inline'", policy["script-src"])
+		self.assertNotIn("report-uri", policy)
+
+	def test_analytics_scripts_are_allowed(self):
+		policy = parse_policy(frontend_server.make_content_security_policy(INDEX_HTML))
+
+		self.assertIn("https://www.googletagmanager.com", policy["script-src"])
+		self.assertIn("https://www.google-analytics.com", policy["script-src"])
+
+	def test_sources_are_not_shared_between_policies(self):
+		frontend_server.make_content_security_policy(INDEX_HTML)
+		policy = parse_policy(frontend_server.make_content_security_policy(""))
+		self.assertEqual(policy["script-src"], frontend_server.CSP_SOURCES["script-src"])
+
+	def test_report_uri(self):
+		policy = parse_policy(frontend_server.make_content_security_policy(
+			INDEX_HTML,
+			report_uri="https://example.com/csp",
+		))
+		self.assertEqual(policy["report-uri"], ["https://example.com/csp"])
+
+
+class TestHtmlHeaders(unittest.TestCase):
+	def setUp(self):
+		directory = tempfile.TemporaryDirectory()
+		self.addCleanup(directory.cleanup)
+		self.index_path = pathlib.Path(directory.name) / "index.html"
+		self.index_path.write_text(INDEX_HTML)
+
+	def test_modes(self):
+		headers = frontend_server.make_html_headers(self.index_path, csp_mode="enforce")
+		self.assertIn("Content-Security-Policy", headers)
+		self.assertNotIn("Content-Security-Policy-Report-Only", headers)
+
+		headers = frontend_server.make_html_headers(
+			self.index_path,
+			csp_mode="report-only",
+			csp_report_uri="/csp-report",
+		)
+		self.assertIn("report-uri /csp-report", headers["Content-Security-Policy-Report-Only"])
+		self.assertNotIn("Content-Security-Policy", headers)
+
+		headers = frontend_server.make_html_headers(self.index_path, csp_mode="off")
+		self.assertEqual(headers["X-Content-Type-Options"], "nosniff")
+		self.assertFalse(any(name.startswith("Content-Security-Policy") for name in headers))
+
+
+class TestRobotsTxt(unittest.TestCase):
+	def test_threads_are_disallowed_by_default(self):
+		lines = frontend_server.make_robots_txt(crawl_threads=False).splitlines()
+		self.assertIn("Disallow: /thread/", lines)
+		self.assertIn("Disallow: /api/", lines)
+
+	def test_crawl_threads(self):
+		lines = frontend_server.make_robots_txt(crawl_threads=True).splitlines()
+		self.assertNotIn("Disallow: /thread/", lines)
+		self.assertIn("Disallow: /api/", lines)
+
+	def test_handler(self):
+		robots_txt = frontend_server.make_robots_txt(crawl_threads=False)
+		response = run(frontend_server.robots_handler(
+			SimpleNamespace(method="GET"),
+			robots_txt=robots_txt,
+		))
+		self.assertEqual(response.text, robots_txt)
+		self.assertEqual(response.content_type, "text/plain")
+
+
+if __name__ == "__main__":
+	unittest.main()
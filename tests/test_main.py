@@ -0,0 +1,84 @@
+import asyncio
+import os
+import pathlib
+import tempfile
+import unittest
+
+from bobbin import main
+
+from support import run
+
+
+class FakeToken:
+	def __init__(self, consumer_key, consumer_secret):
+		self.consumer_key = consumer_key
+		self.consumer_secret = consumer_secret
+		self.regenerations = 0
+
+	def set_credentials(self, consumer_key, consumer_secret):
+		self.consumer_key = consumer_key
+		self.consumer_secret = consumer_secret
+
+	async def regenerate(self):
+		self.regenerations += 1
+
+
+class TestReloadCredentials(unittest.TestCase):
+	def setUp(self):
+		directory = tempfile.TemporaryDirectory()
+		self.addCleanup(directory.cleanup)
+		self.config = pathlib.Path(directory.name) / "bobbin.env"
+		self.write_config("key", mtime=1000)
+		self.token = FakeToken("key", "secret")
+
+	def write_config(self, key, *, mtime):
+		self.config.write_text(f"CONSUMER_KEY={key}\nCONSUMER_SECRET=secret\n")
+		os.utime(self.config, (mtime, mtime))
+
+	def reload_after(self, change):
+		'''
+		Run reload_credentials, make a change to the config file, and give it
+		a chance to notice
+		'''
+		errors = []
+
+		async def test():
+			reloader = asyncio.ensure_future(main.reload_credentials(
+				self.config,
+				self.token,
+				interval=0.001,
+				reload_key=True,
+				reload_secret=True,
+				report_error=lambda error, context: errors.append(error),
+			))
+			await asyncio.sleep(0.01)
+			change()
+			await asyncio.sleep(0.01)
+
+			reloader.cancel()
+			with self.assertRaises(asyncio.CancelledError):
+				await reloader
+
+		run(test())
+		self.assertEqual(errors, [])
+
+	def test_changed_credentials(self):
+		self.reload_after(lambda: self.write_config("new key", mtime=2000))
+
+		self.assertEqual(self.token.consumer_key, "new key")
+		self.assertEqual(self.token.regenerations, 1)
+
+	def test_unchanged_mtime(self):
+		self.reload_after(lambda: self.write_config("new key", mtime=1000))
+
+		self.assertEqual(self.token.consumer_key, "key")
+		self.assertEqual(self.token.regenerations, 0)
+
+	def test_unchanged_credentials(self):
+		self.reload_after(lambda: self.write_config("key", mtime=2000))
+
+		self.assertEqual(self.token.regenerations, 0)
+
+
+if __name__ == "__main__":
+	unittest.main()
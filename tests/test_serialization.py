@@ -0,0 +1,43 @@
+import unittest
+
+from bobbin import serialization
+from bobbin.twitter import Tweet, TwitterUser
+
+TWEETS = [
+	Tweet("1", TwitterUser("10", "someone", "Some One", "blue"), None, None, None, "First 🧵"),
+	Tweet("2", TwitterUser("11", "other", "Other", None), "1", "10", "someone", "Reply", "1"),
+]
+
+
+class TestCodecs(unittest.TestCase):
+	def test_round_trip(self):
+		for name, codec in serialization.CODECS.items():
+			for tweet in TWEETS:
+				with self.subTest(codec=name, tweet=tweet.id):
+					blob = codec.encode(tweet)
+					self.assertTrue(blob.startswith(codec.prefix))
+					self.assertEqual(codec.decode(blob), tweet)
+
+	def test_prefixes_are_distinct(self):
+		prefixes = [codec.prefix for codec in serialization.CODECS.values()]
+		self.assertEqual(len(set(prefixes)), len(prefixes))
+
+	def test_rejects_other_codecs(self):
+		for name, codec in serialization.CODECS.items():
+			for other_name, other_codec in serialization.CODECS.items():
+				if other_name == name:
+					continue
+
+				with self.subTest(codec=name, written_by=other_name):
+					with self.assertRaises(serialization.CodecError):
+						codec.decode(other_codec.encode(TWEETS[0]))
+
+	def test_rejects_unprefixed_blobs(self):
+		for name, codec in serialization.CODECS.items():
+			with self.subTest(codec=name):
+				with self.assertRaises(serialization.CodecError):
+					codec.decode(b'{"id":"1"}')
+
+
+if __name__ == "__main__":
+	unittest.main()
@@ -0,0 +1,264 @@
+import pathlib
+import ssl
+import tempfile
+import unittest
+
+from bobbin import settings
+
+# A self-signed certificate and key for localhost, for testing only
+FIXTURES = pathlib.Path(__file__).parent / "fixtures"
+
+
+class TestParseSize(unittest.TestCase):
+	def test_parse_size(self):
+		cases = [
+			("1024", 1024),
+			("512B", 512),
+			("64KB", 64 * 1024),
+			("256MB", 256 * 1024 * 1024),
+			("2GB", 2 * 1024 * 1024 * 1024),
+		]
+		for size, expected in cases:
+			with self.subTest(size=size):
+				self.assertEqual(settings.parse_size(size), expected)
+
+	def test_invalid_sizes(self):
+		for size in ["", "MB", "1.5GB", "big"]:
+			with self.subTest(size=size):
+				with self.assertRaises(settings.SettingsError):
+					settings.parse_size(size)
+
+
+def server_settings(**overrides):
+	return settings.ServerSettings(**{
+		"host": "0.0.0.0",
+		"port": 8080,
+		"csp_mode": "enforce",
+		"csp_report_uri": None,
+		"tls_cert": None,
+		"tls_key": None,
+		"collapse_after": None,
+		**overrides,
+	})
+
+
+class TestServerSettings(unittest.TestCase):
+	def test_defaults_are_valid(self):
+		server_settings().validate()
+
+	def test_invalid(self):
+		invalid = [
+			dict(port=0),
+			dict(port=65536),
+			dict(tls_cert="cert.pem"),
+			dict(tls_key="key.pem"),
+			dict(collapse_after=0),
+		]
+		for overrides in invalid:
+			with self.subTest(**overrides):
+				with self.assertRaises(settings.SettingsError):
+					server_settings(**overrides).validate()
+
+	def test_plain_http(self):
+		self.assertIsNone(server_settings().make_ssl_context())
+
+	def test_https(self):
+		tls_settings = server_settings(
+			tls_cert=FIXTURES / "localhost-cert.pem",
+			tls_key=FIXTURES / "localhost-key.pem",
+		).validate()
+		self.assertIsInstance(tls_settings.make_ssl_context(), ssl.SSLContext)
+
+	def test_cert_without_key(self):
+		with self.assertRaisesRegex(settings.SettingsError, "must be given together"):
+			server_settings(tls_cert=FIXTURES / "localhost-cert.pem").validate()
+
+	def test_unreadable_cert(self):
+		tls_settings = server_settings(
+			tls_cert=FIXTURES / "missing-cert.pem",
+			tls_key=FIXTURES / "localhost-key.pem",
+		).validate()
+		with self.assertRaises(OSError):
+			tls_settings.make_ssl_context()
+
+	def test_csp_report_uri(self):
+		server_settings(csp_mode="report-only", csp_report_uri="/csp-report").validate()
+		server_settings(csp_report_uri="https://example.com/csp").validate()
+
+		# Without a report uri, violations still show up in the browser console
+		server_settings(csp_mode="report-only").validate()
+
+		invalid = [
+			dict(csp_report_uri=""),
+			dict(csp_report_uri="/csp; script-src *"),
+			dict(csp_report_uri="/a /b"),
+			dict(csp_mode="sometimes"),
+		]
+		for overrides in invalid:
+			with self.subTest(**overrides):
+				with self.assertRaises(settings.SettingsError):
+					server_settings(**overrides).validate()
+
+
+def cache_settings(**overrides):
+	return settings.CacheSettings(**{
+		"size": 1024,
+		"format": "pickle",
+		"min_thread_length": 2,
+		"max_age": None,
+		"prewarm_threads": 0,
+		**overrides,
+	})
+
+
+class TestCacheSettings(unittest.TestCase):
+	def test_defaults_are_valid(self):
+		cache_settings().validate()
+
+	def test_invalid(self):
+		invalid = [
+			dict(size=0),
+			dict(format="yaml"),
+			dict(min_thread_length=0),
+			dict(max_age=0),
+		]
+		for overrides in invalid:
+			with self.subTest(**overrides):
+				with self.assertRaises(settings.SettingsError):
+					cache_settings(**overrides).validate()
+
+	def test_prewarm_needs_max_age(self):
+		cache_settings(prewarm_threads=10, max_age=3600).validate()
+
+		with self.assertRaises(settings.SettingsError):
+			cache_settings(prewarm_threads=10).validate()
+		with self.assertRaises(settings.SettingsError):
+			cache_settings(prewarm_threads=-1, max_age=3600).validate()
+
+
+class TestReadConfigFile(unittest.TestCase):
+	def read(self, text):
+		with tempfile.TemporaryDirectory() as directory:
+			path = pathlib.Path(directory) / "bobbin.env"
+			path.write_text(text)
+			return settings.read_config_file(path)
+
+	def test_read_config_file(self):
+		config = self.read(
+			"# Twitter credentials\n"
+			"CONSUMER_KEY=abc\n"
+			"\n"
+			"  CONSUMER_SECRET = def=ghi  \n"
+			"PORT=\n"
+		)
+		self.assertEqual(config, {
+			"CONSUMER_KEY": "abc",
+			"CONSUMER_SECRET": "def=ghi",
+			"PORT": "",
+		})
+
+	def test_invalid_line(self):
+		with self.assertRaisesRegex(settings.SettingsError, ":2: expected KEY=VALUE"):
+			self.read("CONSUMER_KEY=abc\nCONSUMER_SECRET\n")
+
+	def test_parse_int(self):
+		self.assertEqual(settings.parse_int("8080", "PORT"), 8080)
+		with self.assertRaisesRegex(settings.SettingsError, "PORT must be an integer"):
+			settings.parse_int("http", "PORT")
+
+
+class TestMergeConfig(unittest.TestCase):
+	FILE_CONFIG = {
+		"CONSUMER_KEY": "file key",
+		"CONSUMER_SECRET": "file secret",
+		"HOST": "127.0.0.1",
+		"PORT": "9000",
+		"CACHE_SIZE": "1GB",
+	}
+
+	def merge(self, file_config, **overrides):
+		return settings.merge_config(file_config, **{
+			"key": None,
+			"secret": None,
+			"host": None,
+			"port": None,
+			"cache_size": None,
+			**overrides,
+		})
+
+	def test_file_values(self):
+		self.assertEqual(self.merge(self.FILE_CONFIG), settings.ConfigValues(
+			key="file key",
+			secret="file secret",
+			host="127.0.0.1",
+			port=9000,
+			cache_size="1GB",
+		))
+
+	def test_given_values_take_precedence(self):
+		values = settings.ConfigValues(
+			key="cli key",
+			secret="env secret",
+			host="0.0.0.0",
+			port=8000,
+			cache_size="64MB",
+		)
+		self.assertEqual(self.merge(self.FILE_CONFIG, **values._asdict()), values)
+
+	def test_defaults(self):
+		self.assertEqual(self.merge({}), settings.ConfigValues(
+			key=None,
+			secret=None,
+			host="0.0.0.0",
+			port=8080,
+			cache_size="256MB",
+		))
+
+	def test_invalid_port(self):
+		with self.assertRaisesRegex(settings.SettingsError, "PORT must be an integer"):
+			self.merge({"PORT": "http"})
+
+		# A port given on the command line is already an int
+		self.assertEqual(self.merge({"PORT": "http"}, port=8000).port, 8000)
+
+
+def limit_settings(**overrides):
+	return settings.LimitSettings(**{
+		"max_builds": 32,
+		"timeline_concurrency": 1,
+		"max_show_requests": None,
+		"max_lookup_requests": None,
+		"max_timeline_requests": None,
+		"max_tweet_v2_requests": None,
+		"max_search_requests": None,
+		"token_timeout": 10.0,
+		"build_budget": None,
+		**overrides,
+	})
+
+
+class TestLimitSettings(unittest.TestCase):
+	def test_defaults_are_valid(self):
+		limit_settings().validate()
+		limit_settings(max_show_requests=4, max_timeline_requests=2, build_budget=0.5).validate()
+
+	def test_invalid(self):
+		invalid = [
+			dict(max_builds=0),
+			dict(timeline_concurrency=0),
+			dict(max_show_requests=0),
+			dict(max_lookup_requests=0),
+			dict(max_timeline_requests=0),
+			dict(max_tweet_v2_requests=0),
+			dict(max_search_requests=0),
+			dict(token_timeout=0),
+			dict(build_budget=0),
+		]
+		for overrides in invalid:
+			with self.subTest(**overrides):
+				with self.assertRaises(settings.SettingsError):
+					limit_settings(**overrides).validate()
+
+
+if __name__ == "__main__":
+	unittest.main()
@@ -0,0 +1,74 @@
+import unittest
+
+from bobbin import thread_text
+from bobbin.tweetbox import AuthorCounter, Thread
+from bobbin.twitter import Tweet, TwitterUser
+
+AUTHOR = TwitterUser("1", "some_one", "Some *One*")
+
+
+def make_thread(texts, truncated=False):
+	tweets = [
+		Tweet(str(i), AUTHOR, str(i - 1) if i else None, "1" if i else None, None, text)
+		for i, text in enumerate(texts)
+	]
+	return Thread(tweets, truncated, None, None, None, None, False, AuthorCounter())
+
+
+class TestRenderText(unittest.TestCase):
+	def test_render_text(self):
+		thread = make_thread(["First", "Second\nline"])
+		self.assertEqual(
+			thread_text.render_text(thread, AUTHOR),
+			"Thread by @some_one\n\n"
+			"Some *One* (@some_one):\nFirst\n\n"
+			"Some *One* (@some_one):\nSecond\nline\n",
+		)
+
+	def test_single_tweet_title(self):
+		thread = make_thread(["Only"])
+		self.assertTrue(thread_text.render_text(thread, AUTHOR).startswith("Tweet by @some_one\n"))
+		self.assertTrue(thread_text.render_markdown(thread, AUTHOR).startswith("# Tweet by @some\\_one\n"))
+
+	def test_render_text_truncated(self):
+		thread = make_thread(["Only"], truncated=True)
+		self.assertIn(f"[{thread_text.TRUNCATED_MESSAGE}]", thread_text.render_text(thread, None))
+
+	def test_render_markdown(self):
+		thread = make_thread(["1 < 2\nhttps://twitter.com/some_one/status/0/photo/1"])
+		self.assertEqual(
+			thread_text.render_markdown(thread, None),
+			"# Conversation\n\n"
+			"**Some \\*One\\*** ([@some\\_one](https://twitter.com/some_one/status/0))\n\n"
+			"1 \\< 2  \n<https://twitter.com/some_one/status/0/photo/1>\n",
+		)
+
+	def test_markdown_block_markers(self):
+		cases = [
+			("- not a list", "\\- not a list"),
+			("+ not a list", "\\+ not a list"),
+			("1. not a list", "1\\. not a list"),
+			("10) not a list", "10\\) not a list"),
+			("> not a quote", "\\> not a quote"),
+			("![not an image](x)", "\\!\\[not an image\\](x)"),
+			("# not a heading", "\\# not a heading"),
+			("a - b", "a - b"),
+		]
+		for text, escaped in cases:
+			with self.subTest(text=text):
+				self.assertEqual(thread_text.escape_tweet_markdown(text), escaped)
+
+		self.assertEqual(
+			thread_text.escape_tweet_markdown("first\n- second"),
+			"first  \n\\- second",
+		)
+
+	def test_markdown_links(self):
+		self.assertEqual(
+			thread_text.escape_tweet_markdown("See https://example.com/a_b_c, *really*"),
+			"See <https://example.com/a_b_c>, \\*really\\*",
+		)
+
+
+if __name__ == "__main__":
+	unittest.main()
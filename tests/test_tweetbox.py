@@ -0,0 +1,537 @@
+import asyncio
+import unittest
+from types import SimpleNamespace
+
+from bobbin import tweetbox, twitter
+from bobbin.tweetbox import (
+	AuthorCounter, BuildLimitError, MissingAncestorError, PrewarmQueue,
+	ThreadBuilds, get_thread, make_thread_getter,
+)
+
+from support import (
+	DictCache, FakeResponse, FakeTwitter, make_chain, run, tweet_json,
+)
+
+
+def count_authors(users):
+	counter = AuthorCounter()
+	for user in users:
+		counter.add(SimpleNamespace(user=user))
+	return counter
+
+
+class TestAuthorCounter(unittest.TestCase):
+	def test_classify(self):
+		cases = [
+			("", (None, None, 0.0, 0)),
+			("a", ("a", "a", 1.0, 1)),
+			("aaa", ("a", "a", 1.0, 1)),
+			("aab", ("a", "a", 2 / 3, 2)),
+			("ab", (None, None, 0.5, 2)),
+			("aabbc", (None, None, 0.4, 3)),
+			# The leader needs at least half as many tweets as there are
+			# participants
+			("aabcde", (None, "a", 2 / 6, 5)),
+			("aabc", ("a", "a", 0.5, 3)),
+		]
+		for users, expected in cases:
+			with self.subTest(users=users):
+				self.assertEqual(tuple(count_authors(users).classify()), expected)
+
+
+def build(fake_twitter, tail, **kwargs):
+	return run(get_thread(
+		session=fake_twitter.session,
+		cache=kwargs.pop("cache", None) or DictCache(),
+		token="Bearer token",
+		tail=tail,
+		**kwargs,
+	))
+
+
+def thread_ids(thread):
+	return [tweet.id for tweet in thread.tweets]
+
+
+def timeline_lookups(fake_twitter):
+	return [params["user_id"] for params in fake_twitter.endpoint_requests(twitter.USER_TIMELINE_URL)]
+
+
+class TestCorruptCacheEntries(unittest.TestCase):
+	def test_corrupt_entries_are_misses(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 3))
+		cache = DictCache()
+		build(fake_twitter, "3", cache=cache)
+
+		errors = []
+		cache.data["2"] = b"p1:not a pickle"
+		thread = build(
+			fake_twitter,
+			"3",
+			cache=cache,
+			error_sink=lambda error, context: errors.append(context),
+		)
+
+		self.assertEqual(thread_ids(thread), ["1", "2", "3"])
+		self.assertEqual(thread.stats["cache_misses"], 1)
+		self.assertEqual(thread.sources["2"], "api")
+		self.assertEqual(errors, [{"cache_key": "2"}])
+
+		# The fresh copy replaced the corrupt one
+		self.assertEqual(build(fake_twitter, "3", cache=cache).stats["cache_hits"], 3)
+
+
+class TestSources(unittest.TestCase):
+	def test_sources_and_stats(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 3))
+		cache = DictCache()
+		thread = build(fake_twitter, "3", cache=cache)
+
+		self.assertEqual(thread.sources, {"3": "api", "2": "timeline", "1": "timeline"})
+		self.assertEqual(thread.stats["cache_hits"], 0)
+		self.assertEqual(thread.stats["cache_misses"], 3)
+		self.assertEqual(thread.stats["api_calls"], 2)
+
+		thread = build(fake_twitter, "3", cache=cache)
+
+		self.assertEqual(thread.sources, {"3": "cache", "2": "cache", "1": "cache"})
+		self.assertEqual(thread.stats["cache_hits"], 3)
+		self.assertEqual(thread.stats["api_calls"], 0)
+
+
+class TestMinCacheLength(unittest.TestCase):
+	def test_short_threads_arent_cached(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 2))
+		cache = DictCache()
+		build(fake_twitter, "2", cache=cache, min_cache_length=3)
+
+		self.assertEqual(cache.data, {})
+
+	def test_long_threads_are_cached(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 3))
+		cache = DictCache()
+		build(fake_twitter, "3", cache=cache, min_cache_length=3)
+
+		self.assertEqual(set(cache.data), {"1", "2", "3"})
+
+	def test_context_limits_what_is_cached(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 5))
+		cache = DictCache()
+		build(fake_twitter, "5", cache=cache, context=1, min_cache_length=3)
+
+		self.assertEqual(cache.data, {})
+
+
+class CountingCache(DictCache):
+	def __init__(self):
+		super().__init__()
+		self.writes = []
+
+	async def write(self, key, value):
+		self.writes.append(key)
+		await super().write(key, value)
+
+
+class TestCacheWrites(unittest.TestCase):
+	def test_cached_tweets_arent_rewritten(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 3))
+		cache = CountingCache()
+		build(fake_twitter, "2", cache=cache)
+		self.assertEqual(sorted(cache.writes), ["1", "2"])
+
+		# Only the new tail is written when the thread grows
+		cache.writes.clear()
+		build(fake_twitter, "3", cache=cache)
+		self.assertEqual(cache.writes, ["3"])
+
+	def test_refresh_rewrites_everything(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 3))
+		cache = CountingCache()
+		build(fake_twitter, "3", cache=cache)
+
+		cache.writes.clear()
+		thread = build(fake_twitter, "3", cache=cache, refresh=True)
+		self.assertEqual(sorted(cache.writes), ["1", "2", "3"])
+		self.assertEqual(thread.stats["cache_hits"], 0)
+
+
+class TestContext(unittest.TestCase):
+	def test_context_limits_ancestors(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 5))
+		thread = build(fake_twitter, "5", context=2)
+
+		self.assertEqual(thread_ids(thread), ["3", "4", "5"])
+		self.assertFalse(thread.truncated)
+
+
+def deleted_ancestor_twitter():
+	tweets = make_chain([1] * 4)
+	del tweets[1]
+	return FakeTwitter(tweets)
+
+
+class TestMissingAncestors(unittest.TestCase):
+	def test_thread_is_truncated(self):
+		thread = build(deleted_ancestor_twitter(), "4")
+
+		self.assertEqual(thread_ids(thread), ["3", "4"])
+		self.assertTrue(thread.truncated)
+
+	def test_strict_threads_fail(self):
+		with self.assertRaises(MissingAncestorError):
+			build(deleted_ancestor_twitter(), "4", allow_missing=False)
+
+	def test_threads_with_a_head_fail(self):
+		with self.assertRaises(MissingAncestorError):
+			build(deleted_ancestor_twitter(), "4", head="1")
+
+	def test_missing_tail_fails(self):
+		with self.assertRaises(twitter.DeletedTweetError):
+			build(deleted_ancestor_twitter(), "2")
+
+
+class TestAuthorOnly(unittest.TestCase):
+	def test_stops_at_other_authors(self):
+		fake_twitter = FakeTwitter(make_chain([2, 2, 1, 1, 1]))
+		thread = build(fake_twitter, "5", author_only=True)
+
+		self.assertEqual(thread_ids(thread), ["3", "4", "5"])
+		self.assertFalse(thread.truncated)
+		self.assertNotIn("2", timeline_lookups(fake_twitter))
+
+	def test_head_by_another_author_is_invalid(self):
+		fake_twitter = FakeTwitter(make_chain([2, 2, 1, 1, 1]))
+		with self.assertRaises(tweetbox.InvalidThreadError):
+			build(fake_twitter, "5", head="1", author_only=True)
+
+
+class TestLookupFallback(unittest.TestCase):
+	def test_rate_limited_show_falls_back_on_lookup(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 3), show_rate_limited=True)
+		thread = build(fake_twitter, "3")
+
+		self.assertEqual(thread_ids(thread), ["1", "2", "3"])
+		self.assertEqual(len(fake_twitter.endpoint_requests(twitter.TWEET_LOOKUP_URL)), 1)
+		self.assertEqual(thread.stats["api_calls"], 3)
+
+	def test_lookup_only_after_rate_limiting(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 3))
+		build(fake_twitter, "3")
+
+		self.assertEqual(fake_twitter.endpoint_requests(twitter.TWEET_LOOKUP_URL), [])
+
+	def test_missing_tail(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 3), show_rate_limited=True)
+		with self.assertRaises(twitter.NoSuchTweetError):
+			build(fake_twitter, "4")
+
+
+class TestEndpointSlots(unittest.TestCase):
+	def test_saturated_endpoints_dont_block_others(self):
+		fake_twitter = FakeTwitter(make_chain([1]))
+		endpoint_slots = {
+			twitter.Endpoint.show: asyncio.Semaphore(1),
+			twitter.Endpoint.user_timeline: asyncio.Semaphore(1),
+		}
+
+		async def build_with_slots():
+			return await get_thread(
+				session=fake_twitter.session,
+				cache=DictCache(),
+				token="Bearer token",
+				tail="1",
+				endpoint_slots=endpoint_slots,
+			)
+
+		async def test():
+			async with endpoint_slots[twitter.Endpoint.user_timeline]:
+				thread = await asyncio.wait_for(build_with_slots(), timeout=1)
+				self.assertEqual(thread_ids(thread), ["1"])
+
+			async with endpoint_slots[twitter.Endpoint.show]:
+				with self.assertRaises(asyncio.TimeoutError):
+					await asyncio.wait_for(build_with_slots(), timeout=0.01)
+
+		run(test())
+
+	def test_lookup_fallback_has_its_own_slots(self):
+		fake_twitter = FakeTwitter(make_chain([1]), show_rate_limited=True)
+		lookup_slot = asyncio.Semaphore(1)
+
+		async def test():
+			async with lookup_slot:
+				with self.assertRaises(asyncio.TimeoutError):
+					await asyncio.wait_for(get_thread(
+						session=fake_twitter.session,
+						cache=DictCache(),
+						token="Bearer token",
+						tail="1",
+						endpoint_slots={twitter.Endpoint.lookup: lookup_slot},
+					), timeout=0.01)
+
+			self.assertEqual(len(fake_twitter.endpoint_requests(twitter.TWEET_URL)), 1)
+			self.assertEqual(fake_twitter.endpoint_requests(twitter.TWEET_LOOKUP_URL), [])
+
+		run(test())
+
+
+class TestBackgroundWrites(unittest.TestCase):
+	def test_builds_dont_wait_for_writes(self):
+		release = asyncio.Event()
+		written = asyncio.Event()
+
+		class SlowCache(DictCache):
+			async def write(self, key, value):
+				await release.wait()
+				await super().write(key, value)
+				if len(self.data) == 3:
+					written.set()
+
+		fake_twitter = FakeTwitter(make_chain([1] * 3))
+		cache = SlowCache()
+		get_thread = make_thread_getter(
+			session=fake_twitter.session,
+			cache=cache,
+			token="Bearer token",
+			await_writes=False,
+		)
+
+		async def test():
+			thread = await asyncio.wait_for(get_thread(tail="3"), timeout=1)
+			self.assertEqual(thread_ids(thread), ["1", "2", "3"])
+			self.assertEqual(cache.data, {})
+
+			# The writes carry on after the build
+			release.set()
+			await asyncio.wait_for(written.wait(), timeout=1)
+			self.assertEqual(set(cache.data), {"1", "2", "3"})
+
+		run(test())
+
+
+class TestExhaustedTimelines(unittest.TestCase):
+	def test_skips_timelines_that_came_back_empty(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 4), timelines=False)
+		thread = build(fake_twitter, "4")
+
+		self.assertEqual(thread_ids(thread), ["1", "2", "3", "4"])
+		self.assertEqual(timeline_lookups(fake_twitter), ["1"])
+		self.assertEqual(len(fake_twitter.endpoint_requests(twitter.TWEET_URL)), 4)
+
+
+class TestPrefetchHeuristic(unittest.TestCase):
+	def test_stops_prefetching_when_it_rarely_helps(self):
+		# A conversation between two users, who each tweet a lot in between
+		# their replies, so the replies are never in a 100 tweet timeline
+		# lookup.
+		conversation = [
+			tweet_json(
+				1000 * i,
+				i % 2,
+				parent_id=1000 * (i - 1) if i > 1 else None,
+				parent_user_id=(i - 1) % 2 if i > 1 else None,
+			)
+			for i in range(1, 9)
+		]
+		chatter = [
+			tweet_json(1000 * i + offset, user_id)
+			for i in range(1, 8)
+			for user_id in (0, 1)
+			for offset in range(1 + 200 * user_id, 151 + 200 * user_id)
+		]
+		fake_twitter = FakeTwitter(conversation + chatter)
+		thread = build(fake_twitter, "8000")
+
+		self.assertEqual(thread_ids(thread), [str(1000 * i) for i in range(1, 9)])
+		self.assertEqual(len(timeline_lookups(fake_twitter)), tweetbox.PREFETCH_SAMPLE_SIZE)
+
+	def test_keeps_prefetching_when_it_helps(self):
+		# Long threads need a timeline lookup per 100 tweets
+		fake_twitter = FakeTwitter(make_chain([1] * 250))
+		thread = build(fake_twitter, "250")
+
+		self.assertEqual(len(thread.tweets), 250)
+		self.assertEqual(len(timeline_lookups(fake_twitter)), 3)
+
+
+class TestConversationSearch(unittest.TestCase):
+	def test_counts_each_page(self):
+		fake_twitter = FakeTwitter(make_chain([1] * 5), page_size=2)
+		thread = build(fake_twitter, "5", api_v2=True)
+
+		self.assertEqual([tweet.id for tweet in thread.tweets], ["1", "2", "3", "4", "5"])
+		self.assertEqual(len(fake_twitter.endpoint_requests(twitter.SEARCH_RECENT_URL)), 3)
+		self.assertEqual(thread.stats["api_calls"], 4)
+		self.assertEqual(thread.sources["4"], "conversation")
+
+	def test_falls_back_on_timelines(self):
+		# Recent search only covers about a week
+		fake_twitter = FakeTwitter(make_chain([1] * 5), search=False)
+		thread = build(fake_twitter, "5", api_v2=True)
+
+		self.assertEqual([tweet.id for tweet in thread.tweets], ["1", "2", "3", "4", "5"])
+		self.assertEqual(thread.sources["4"], "timeline")
+		self.assertEqual(thread.stats["api_calls"], 3)
+
+	def test_failed_pages(self):
+		cases = [
+			# An app without search access
+			(None, 403, 1),
+			# A twitter outage partway through
+			("2", 503, 2),
+		]
+		for failing_token, status, searches in cases:
+			with self.subTest(status=status):
+				fake_twitter = FakeTwitter(make_chain([1] * 5), page_size=2)
+				respond = fake_twitter.respond
+
+				def fail_search(method, url, params):
+					if url == twitter.SEARCH_RECENT_URL and params.get("next_token") == failing_token:
+						return FakeResponse({"title": "Error"}, status=status)
+					return respond(method, url, params)
+
+				fake_twitter.session.respond = fail_search
+				errors = []
+				thread = build(
+					fake_twitter,
+					"5",
+					api_v2=True,
+					error_sink=lambda error, context: errors.append(context),
+				)
+
+				self.assertEqual(thread_ids(thread), ["1", "2", "3", "4", "5"])
+				self.assertEqual(len(fake_twitter.endpoint_requests(twitter.SEARCH_RECENT_URL)), searches)
+				self.assertEqual(thread.sources["4"], "timeline")
+				self.assertEqual(errors, [{"conversation_id": "1"}])
+
+
+class TestThreadBuilds(unittest.TestCase):
+	def test_builds_keep_their_slot_until_done(self):
+		async def test():
+			release = asyncio.Event()
+			calls = []
+
+			async def get_thread(*, tail):
+				calls.append(tail)
+				await release.wait()
+				return tail
+
+			builds = ThreadBuilds(get_thread, max_builds=1)
+			build = builds.start(tail="1")
+
+			# The request that started the build gives up waiting, but the
+			# build still has the only slot
+			await asyncio.wait([build], timeout=0)
+			self.assertFalse(build.done())
+			with self.assertRaises(BuildLimitError):
+				builds.start(tail="2")
+
+			# Requests for the same thread join the running build
+			self.assertIs(builds.start(tail="1"), build)
+
+			release.set()
+			self.assertEqual(await build, "1")
+			self.assertEqual(builds.running, {})
+
+			self.assertEqual(await builds.start(tail="2"), "2")
+			self.assertEqual(calls, ["1", "2"])
+
+		run(test())
+
+	def test_failed_builds_free_their_slot(self):
+		async def test():
+			async def get_thread(*, tail):
+				raise ValueError(tail)
+
+			errors = []
+			builds = ThreadBuilds(
+				get_thread,
+				max_builds=1,
+				error_sink=lambda error, context: errors.append((error, context)),
+			)
+			build = builds.start(tail="1")
+			await asyncio.wait([build])
+
+			self.assertEqual(builds.running, {})
+
+			# Nobody waited for the build, so its error is reported
+			[(error, context)] = errors
+			self.assertIsInstance(error, ValueError)
+			self.assertEqual(context, {"task": "thread build", "tail": "1"})
+
+			with self.assertRaises(ValueError):
+				await builds.start(tail="1")
+
+		run(test())
+
+
+class TestPrewarmQueue(unittest.TestCase):
+	def setUp(self):
+		self.fake_twitter = FakeTwitter(make_chain([1] * 3) + make_chain([2] * 3, start=11))
+		self.cache = DictCache()
+
+		def thread_getter(**kwargs):
+			return get_thread(
+				session=self.fake_twitter.session,
+				cache=self.cache,
+				token="Bearer token",
+				**kwargs,
+			)
+
+		self.builds = ThreadBuilds(thread_getter, max_builds=1)
+		self.prewarm = PrewarmQueue(self.builds, max_size=2, interval=60)
+
+	def test_bounded(self):
+		self.prewarm.add(tail="1")
+		self.prewarm.add(tail="2")
+		self.prewarm.add(tail="1")
+		self.prewarm.add(tail="3")
+
+		# 2 was the least recently requested
+		self.assertEqual(list(self.prewarm.threads.values()), [dict(tail="1"), dict(tail="3")])
+
+	def test_rebuilds_refresh_the_cache(self):
+		run(self.builds.start(tail="3"))
+		self.assertEqual(len(self.cache.data), 3)
+
+		# Rebuilding from the cache wouldn't make any requests, or restart
+		# the cached tweets' max age
+		self.cache.data = {tweet_id: "stale" for tweet_id in self.cache.data}
+		requests = len(self.fake_twitter.requests)
+
+		self.prewarm.add(tail="3")
+		run(self.prewarm.rebuild_all())
+
+		self.assertGreater(len(self.fake_twitter.requests), requests)
+		self.assertNotIn("stale", self.cache.data.values())
+
+	def test_drops_deleted_threads(self):
+		self.prewarm.add(tail="13")
+		self.prewarm.add(tail="99")
+		run(self.prewarm.rebuild_all())
+
+		self.assertEqual(list(self.prewarm.threads.values()), [dict(tail="13")])
+
+	def test_skips_threads_when_builds_are_busy(self):
+		release = asyncio.Event()
+
+		async def slow_get_thread(**kwargs):
+			await release.wait()
+
+		builds = ThreadBuilds(slow_get_thread, max_builds=1)
+		prewarm = PrewarmQueue(builds, max_size=2, interval=60)
+		prewarm.add(tail="1")
+
+		async def test():
+			build = builds.start(tail="2")
+			await prewarm.rebuild_all()
+			self.assertEqual(list(builds.running.values()), [build])
+
+			release.set()
+			await build
+
+		run(test())
+
+
+if __name__ == "__main__":
+	unittest.main()
@@ -0,0 +1,382 @@
+import asyncio
+import json
+import unittest
+
+from bobbin import twitter
+
+from support import FakeResponse, FakeSession, FakeTwitter, run, tweet_json
+
+
+def error_response(code, *, status=200, headers=None):
+	return FakeResponse(
+		{"errors": [{"code": code, "message": f"error {code}"}]},
+		status=status,
+		headers=headers,
+	)
+
+
+class TestReadResponse(unittest.TestCase):
+	def test_success(self):
+		result = run(twitter.read_response(FakeResponse({"id_str": "1"})))
+		self.assertEqual(result, {"id_str": "1"})
+
+	def test_error_codes(self):
+		cases = [
+			(50, twitter.NoSuchUserError),
+			(63, twitter.SuspendedUserError),
+			(144, twitter.DeletedTweetError),
+			(179, twitter.ProtectedTweetError),
+			(32, twitter.AuthenticationError),
+			(89, twitter.InvalidTokenError),
+			(215, twitter.InvalidTokenError),
+		]
+		for code, error_type in cases:
+			with self.subTest(code=code):
+				with self.assertRaises(error_type) as caught:
+					run(twitter.read_response(error_response(code, status=403)))
+				self.assertEqual(caught.exception.args, (code, f"error {code}"))
+
+	def test_page_not_found_is_endpoint_specific(self):
+		with self.assertRaises(twitter.NoSuchUserError):
+			run(twitter.read_response(error_response(34, status=404), not_found=twitter.NoSuchUserError))
+
+		with self.assertRaises(twitter.TwitterIDError):
+			run(twitter.read_response(error_response(34, status=404)))
+
+		# Code 34 doesn't mean the tweet ever existed
+		with self.assertRaises(twitter.NoSuchTweetError) as caught:
+			run(twitter.read_response(error_response(34, status=404), not_found=twitter.NoSuchTweetError))
+		self.assertNotIsInstance(caught.exception, twitter.DeletedTweetError)
+
+	def test_rate_limit_retry_after(self):
+		response = error_response(88, status=429, headers={"x-rate-limit-reset": "0"})
+		with self.assertRaises(twitter.RateLimitError) as caught:
+			run(twitter.read_response(response))
+		self.assertEqual(caught.exception.retry_after, 0)
+
+	def test_bare_statuses(self):
+		with self.assertRaises(twitter.AuthenticationError):
+			run(twitter.read_response(FakeResponse({}, status=401)))
+
+		for status in (420, 429):
+			with self.subTest(status=status):
+				with self.assertRaises(twitter.RateLimitError) as caught:
+					run(twitter.read_response(FakeResponse({}, status=status)))
+				self.assertEqual(caught.exception.args[0], status)
+				self.assertIsNone(caught.exception.retry_after)
+
+	def test_unknown_codes_fall_back_to_the_status(self):
+		result = run(twitter.read_response(error_response(999)))
+		self.assertEqual(result["errors"][0]["code"], 999)
+
+		with self.assertRaises(Exception):
+			run(twitter.read_response(error_response(999, status=500)))
+
+
+class TestVerifiedType(unittest.TestCase):
+	def test_verified_types(self):
+		cases = [
+			({"verified_type": "blue"}, "blue"),
+			({"verified_type": "business"}, "business"),
+			({"verified_type": "government"}, "government"),
+			# Legacy verification only has the flag
+			({"verified": True}, "blue"),
+			({"verified": True, "verified_type": "business"}, "business"),
+			({"verified": False, "verified_type": "none"}, None),
+			({"verified": False}, None),
+			({}, None),
+		]
+		for fields, verified_type in cases:
+			with self.subTest(**fields):
+				self.assertEqual(twitter.get_verified_type(fields), verified_type)
+
+				user = twitter.TwitterUser.from_user_json({
+					"id_str": "10",
+					"screen_name": "user10",
+					"name": "User 10",
+					**fields,
+				})
+				self.assertEqual(user.verified, verified_type)
+
+				user = twitter.TwitterUser.from_user_v2_json({
+					"id": "10",
+					"username": "user10",
+					"name": "User 10",
+					**fields,
+				})
+				self.assertEqual(user.verified, verified_type)
+
+
+class TestGetTweetText(unittest.TestCase):
+	def test_unescapes_before_slicing(self):
+		# The range is in terms of the unescaped text, so slicing the escaped
+		# text would cut "&amp;" short.
+		blob = {
+			"full_text": "@someone Fish &amp; chips 🐟🍟",
+			"display_text_range": [9, 24],
+		}
+		self.assertEqual(twitter.get_tweet_text(blob), "Fish & chips 🐟🍟")
+
+	def test_falls_back_to_text(self):
+		self.assertEqual(twitter.get_tweet_text({"text": "1 &lt; 2"}), "1 < 2")
+
+	def test_appends_media_links(self):
+		photo = "https://twitter.com/someone/status/1/photo/1"
+		video = "https://twitter.com/someone/status/1/video/1"
+		blob = {
+			"full_text": "Look https://t.co/abc",
+			"display_text_range": [0, 4],
+			"entities": {"media": [{"url": "https://t.co/abc", "expanded_url": photo}]},
+			"extended_entities": {"media": [
+				{"url": "https://t.co/abc", "expanded_url": photo},
+				{"url": "https://t.co/abc", "expanded_url": photo},
+				{"url": "https://t.co/def"},
+				{"url": "https://t.co/ghi", "expanded_url": video},
+			]},
+		}
+		self.assertEqual(
+			twitter.get_tweet_text(blob),
+			f"Look\n{photo}\nhttps://t.co/def\n{video}",
+		)
+
+	def test_single_photo_with_only_entities(self):
+		photo = "https://twitter.com/user10/status/1/photo/1"
+		blob = tweet_json(1, 10, text="Look https://t.co/abc")
+		blob["display_text_range"] = [0, 4]
+		blob["entities"] = {"media": [{"url": "https://t.co/abc", "expanded_url": photo}]}
+
+		self.assertEqual(twitter.Tweet.from_tweet_json(blob).text, f"Look\n{photo}")
+
+	def test_extended_entities_are_preferred(self):
+		photos = [f"https://twitter.com/user10/status/1/photo/{i}" for i in (1, 2)]
+		blob = tweet_json(1, 10, text="Look https://t.co/abc")
+		blob["display_text_range"] = [0, 4]
+		blob["entities"] = {"media": [{"url": "https://t.co/abc", "expanded_url": photos[0]}]}
+		blob["extended_entities"] = {"media": [
+			{"url": "https://t.co/abc", "expanded_url": photo} for photo in photos
+		]}
+
+		self.assertEqual(twitter.Tweet.from_tweet_json(blob).text, "\n".join(["Look", *photos]))
+
+
+class TestGenerateBearerToken(unittest.TestCase):
+	def generate(self, response):
+		session = FakeSession(lambda method, url, params: response)
+		return run(twitter.generate_bearer_token(
+			session=session,
+			consumer_key="key",
+			consumer_secret="secret",
+		))
+
+	def test_bearer_token(self):
+		response = FakeResponse({"token_type": "bearer", "access_token": "abc"})
+		self.assertEqual(self.generate(response), "Bearer abc")
+
+	def test_oversized_response(self):
+		# There's no Content-Length to go by, so the body has to be counted
+		padding = "x" * twitter.MAX_TOKEN_RESPONSE_SIZE
+		response = FakeResponse({"token_type": "bearer", "access_token": padding})
+		with self.assertRaisesRegex(twitter.TokenError, "too large"):
+			self.generate(response)
+
+	def test_response_at_the_size_limit(self):
+		blob = {"token_type": "bearer", "access_token": ""}
+		padding = twitter.MAX_TOKEN_RESPONSE_SIZE - len(json.dumps(blob))
+		blob["access_token"] = "x" * padding
+
+		response = FakeResponse(blob)
+		self.assertEqual(len(response.body), twitter.MAX_TOKEN_RESPONSE_SIZE)
+		self.assertEqual(self.generate(response), f"Bearer {'x' * padding}")
+
+	def test_timeout(self):
+		timeouts = []
+
+		class StalledStream:
+			async def read(self, n=-1):
+				# What aiohttp raises when the request's timeout runs out
+				raise asyncio.TimeoutError()
+
+		def post(url, *, timeout=None, **kwargs):
+			timeouts.append(timeout)
+			response = FakeResponse({})
+			response.content = StalledStream()
+			return response
+
+		session = FakeSession(None)
+		session.post = post
+		with self.assertRaises(twitter.TokenTimeoutError):
+			run(twitter.generate_bearer_token(
+				session=session,
+				consumer_key="key",
+				consumer_secret="secret",
+				timeout=0.5,
+			))
+		self.assertEqual(timeouts[0].total, 0.5)
+
+	def test_invalid_responses(self):
+		bodies = [
+			b"<html>Over capacity</html>",
+			b"[]",
+			b'{"token_type": "mac", "access_token": "abc"}',
+			b'{"token_type": "bearer"}',
+		]
+		for body in bodies:
+			with self.subTest(body=body):
+				with self.assertRaises(twitter.TokenError):
+					self.generate(FakeResponse(body))
+
+	def test_error_status(self):
+		with self.assertRaises(twitter.TokenError):
+			self.generate(FakeResponse({}, status=503))
+
+
+class TestAuthorizedRequest(unittest.TestCase):
+	def setUp(self):
+		# The token endpoint hands out a new token each time it's asked
+		tokens = iter(["first", "second", "third"])
+		self.session = FakeSession(lambda method, url, params: FakeResponse({
+			"token_type": "bearer",
+			"access_token": next(tokens),
+		}))
+		self.token = twitter.Token(self.session, "key", "secret")
+
+	def request_with(self, errors):
+		'''
+		Make a request that fails with each of errors in turn, and then
+		succeeds. Returns the bearer tokens it was called with.
+		'''
+		errors = list(errors)
+		used_tokens = []
+
+		async def request(bearer_token):
+			used_tokens.append(bearer_token)
+			if errors:
+				raise errors.pop(0)
+			return bearer_token
+
+		run(twitter.authorized_request(self.token, request))
+		return used_tokens
+
+	def test_refreshes_invalid_tokens(self):
+		for code in (89, 215):
+			with self.subTest(code=code):
+				self.setUp()
+				used_tokens = self.request_with([twitter.InvalidTokenError(code, "Invalid or expired token.")])
+				self.assertEqual(used_tokens, ["Bearer first", "Bearer second"])
+
+	def test_retries_once(self):
+		with self.assertRaises(twitter.InvalidTokenError):
+			self.request_with([
+				twitter.InvalidTokenError(89, "Invalid or expired token."),
+				twitter.InvalidTokenError(89, "Invalid or expired token."),
+			])
+		self.assertEqual(len(self.session.requests), 2)
+
+	def test_other_authentication_errors_are_raised(self):
+		for error in [
+			twitter.AuthenticationError(401, "Unauthorized"),
+			twitter.AuthenticationError(32, "Could not authenticate you."),
+		]:
+			with self.subTest(error=error):
+				with self.assertRaises(twitter.AuthenticationError):
+					self.request_with([error])
+
+		# The first token was never replaced
+		self.assertEqual(len(self.session.requests), 1)
+
+
+class TestStaticToken(unittest.TestCase):
+	def test_authorization_header(self):
+		fake_twitter = FakeTwitter([tweet_json(1, 10)])
+		token = twitter.StaticToken("abc")
+
+		run(twitter.get_tweet(session=fake_twitter.session, token=token, tweet_id="1"))
+		self.assertEqual(fake_twitter.session.headers[0]["Authorization"], "Bearer abc")
+
+	def test_never_generates_tokens(self):
+		token = twitter.StaticToken("abc")
+		token.set_credentials("key", "secret")
+
+		self.assertEqual(run(token.get_token()), "Bearer abc")
+		self.assertEqual(run(token.refresh("Bearer abc")), "Bearer abc")
+
+
+class TestGetTweet(unittest.TestCase):
+	def get_tweet(self, get_tweet, fake_twitter, tweet_id):
+		return run(get_tweet(session=fake_twitter.session, token="Bearer token", tweet_id=tweet_id))
+
+	def test_show(self):
+		fake_twitter = FakeTwitter([tweet_json(1, 10)])
+		self.assertEqual(self.get_tweet(twitter.get_tweet, fake_twitter, "1").id, "1")
+
+		with self.assertRaises(twitter.DeletedTweetError):
+			self.get_tweet(twitter.get_tweet, fake_twitter, "2")
+
+	def test_lookup(self):
+		fake_twitter = FakeTwitter([tweet_json(1, 10)])
+		self.assertEqual(self.get_tweet(twitter.lookup_tweet, fake_twitter, "1").id, "1")
+
+		# Lookup doesn't say why a tweet is missing
+		with self.assertRaises(twitter.NoSuchTweetError) as caught:
+			self.get_tweet(twitter.lookup_tweet, fake_twitter, "2")
+		self.assertNotIsInstance(caught.exception, twitter.DeletedTweetError)
+
+
+def tweet_v2_json(tweet_id, user_id, parent_id=None, parent_user_id=None, text=None):
+	blob = {
+		"id": str(tweet_id),
+		"author_id": str(user_id),
+		"conversation_id": "1",
+		"text": text if text is not None else f"tweet {tweet_id}",
+	}
+	if parent_id is not None:
+		blob["referenced_tweets"] = [{"type": "replied_to", "id": str(parent_id)}]
+		blob["in_reply_to_user_id"] = str(parent_user_id)
+	return blob
+
+
+def user_v2_json(user_id):
+	return {"id": str(user_id), "username": f"user{user_id}", "name": f"User {user_id}"}
+
+
+class TestTweetV2(unittest.TestCase):
+	def test_from_tweet_v2_json(self):
+		users = {user["id"]: twitter.TwitterUser.from_user_v2_json(user) for user in [
+			user_v2_json(10),
+			user_v2_json(11),
+		]}
+		tweet = twitter.Tweet.from_tweet_v2_json(
+			tweet_v2_json(2, 11, parent_id=1, parent_user_id=10, text="@user10 @user12 Sure &amp; thanks"),
+			users,
+		)
+
+		self.assertEqual(tweet.id, "2")
+		self.assertEqual(tweet.user.handle, "user11")
+		self.assertEqual(tweet.parent_id, "1")
+		self.assertEqual(tweet.parent_user_id, "10")
+		self.assertEqual(tweet.parent_user_handle, "user10")
+		self.assertEqual(tweet.text, "Sure & thanks")
+		self.assertEqual(tweet.conversation_id, "1")
+
+	def test_missing_author(self):
+		with self.assertRaises(twitter.NoSuchUserError):
+			twitter.Tweet.from_tweet_v2_json(tweet_v2_json(1, 10), {})
+
+	def test_get_tweet_v2_with_missing_author(self):
+		session = FakeSession(lambda method, url, params: FakeResponse({
+			"data": tweet_v2_json(1, 10),
+			"includes": {"users": []},
+		}))
+		with self.assertRaises(twitter.TwitterIDError):
+			run(twitter.get_tweet_v2(session=session, token="Bearer token", tweet_id="1"))
+
+
+class TestInterning(unittest.TestCase):
+	def test_users_are_shared(self):
+		first = twitter.Tweet.from_tweet_json(tweet_json(1, 10))
+		second = twitter.Tweet.from_tweet_json(tweet_json(2, 10))
+		self.assertIs(first.user, second.user)
+
+
+if __name__ == "__main__":
+	unittest.main()
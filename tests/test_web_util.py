@@ -0,0 +1,83 @@
+import unittest
+from types import SimpleNamespace
+
+from aiohttp import web
+
+from bobbin import web_util
+
+from support import run
+
+ETAG = '"0123456789abcdef"'
+
+
+def make_request(if_none_match=None):
+	headers = {} if if_none_match is None else {"If-None-Match": if_none_match}
+	return SimpleNamespace(headers=headers)
+
+
+class TestETags(unittest.TestCase):
+	def test_make_etag(self):
+		etag = web_util.make_etag("some text")
+		self.assertEqual(etag, web_util.make_etag("some text"))
+		self.assertNotEqual(etag, web_util.make_etag("other text"))
+		self.assertRegex(etag, r'^"[0-9a-f]{32}"$')
+
+	def test_etag_matches(self):
+		cases = [
+			(None, False),
+			(ETAG, True),
+			(f"W/{ETAG}", True),
+			("*", True),
+			(f'"other", {ETAG}', True),
+			(f'"other",{ETAG}', True),
+			('"other"', False),
+			(ETAG.strip('"'), False),
+			("", False),
+		]
+		for if_none_match, expected in cases:
+			with self.subTest(if_none_match=if_none_match):
+				self.assertEqual(
+					web_util.etag_matches(make_request(if_none_match), ETAG),
+					expected,
+				)
+
+	def test_conditional_response(self):
+		etag = web_util.make_etag("body")
+
+		response = web_util.conditional_response(
+			make_request(),
+			text="body",
+			content_type="text/plain",
+			headers={"X-Other": "1"},
+		)
+		self.assertEqual(response.headers["ETag"], etag)
+		self.assertEqual(response.headers["X-Other"], "1")
+
+		with self.assertRaises(web.HTTPNotModified) as caught:
+			web_util.conditional_response(make_request(etag), text="body", content_type="text/plain")
+		self.assertEqual(caught.exception.headers["ETag"], etag)
+
+
+class TestReportErrors(unittest.TestCase):
+	def handle(self, error):
+		reports = []
+
+		@web_util.report_errors(lambda error, context: reports.append((error, context)))
+		async def handler(request):
+			raise error
+
+		request = SimpleNamespace(method="GET", path="/api/thread")
+		with self.assertRaises(type(error)):
+			run(handler(request))
+		return reports
+
+	def test_errors_are_reported(self):
+		error = KeyError("oops")
+		self.assertEqual(self.handle(error), [(error, {"method": "GET", "path": "/api/thread"})])
+
+	def test_http_exceptions_are_not_reported(self):
+		self.assertEqual(self.handle(web.HTTPNotFound()), [])
+
+
+if __name__ == "__main__":
+	unittest.main()